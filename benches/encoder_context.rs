@@ -0,0 +1,44 @@
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use xdelta3::{encode, EncoderContext};
+
+/// A 64 KiB dictionary of records, and messages of 1 KiB resembling a part of it.
+fn data() -> (Vec<u8>, Vec<Vec<u8>>) {
+    let dictionary: Vec<u8> = (0..2048u32)
+        .flat_map(|i| format!("{{\"id\":{:08},\"kind\":\"{}\"}}\n", i, i % 7).into_bytes())
+        .take(64 << 10)
+        .collect();
+    let messages = (0..64)
+        .map(|i| {
+            let mut m = dictionary[i * 1000..i * 1000 + 1024].to_vec();
+            m[100..108].copy_from_slice(format!("{:08}", i * 31).as_bytes());
+            m
+        })
+        .collect();
+    (dictionary, messages)
+}
+
+fn small_messages(c: &mut Criterion) {
+    let (dictionary, messages) = data();
+    let mut group = c.benchmark_group("1 KiB messages");
+    group.throughput(Throughput::Bytes(1024));
+
+    group.bench_function("encode", |b| {
+        let mut i = 0;
+        b.iter(|| {
+            i = (i + 1) % messages.len();
+            encode(&messages[i], &dictionary).unwrap()
+        })
+    });
+    group.bench_function("EncoderContext", |b| {
+        let mut context = EncoderContext::new(&dictionary).unwrap();
+        let mut i = 0;
+        b.iter(|| {
+            i = (i + 1) % messages.len();
+            context.encode(&messages[i]).unwrap()
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, small_messages);
+criterion_main!(benches);
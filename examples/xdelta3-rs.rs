@@ -1,10 +1,13 @@
 use async_std::fs::File;
 use structopt::StructOpt;
+use xdelta3::stats::{PatchStats, Section};
 
 #[derive(Debug, StructOpt)]
 enum Mode {
     Encode,
     Decode,
+    /// Prints what the patch given with -i is made of
+    Stats,
 }
 
 #[derive(Debug, StructOpt)]
@@ -15,15 +18,22 @@ struct Opt {
     #[structopt(short = "i")]
     input: String,
     #[structopt(short = "s")]
-    source: String,
+    source: Option<String>,
     #[structopt(short = "o")]
-    output: String,
+    output: Option<String>,
 }
 
 async fn run(opt: Opt) {
+    if let Mode::Stats = opt.mode {
+        return stats(&opt).await;
+    }
     let input = File::open(&opt.input).await.expect("File::open");
-    let source = File::open(&opt.source).await.expect("File::open");
-    let out = File::create(&opt.output).await.expect("File::create");
+    let source = File::open(opt.source.as_ref().expect("-s is required"))
+        .await
+        .expect("File::open");
+    let out = File::create(opt.output.as_ref().expect("-o is required"))
+        .await
+        .expect("File::create");
 
     match opt.mode {
         Mode::Decode => {
@@ -36,7 +46,86 @@ async fn run(opt: Opt) {
                 .await
                 .expect("failed to encode");
         }
+        Mode::Stats => unreachable!(),
+    }
+}
+
+async fn stats(opt: &Opt) {
+    let patch = async_std::fs::read(&opt.input).await.expect("fs::read");
+    let source_len = match &opt.source {
+        Some(source) => Some(
+            async_std::fs::metadata(source)
+                .await
+                .expect("fs::metadata")
+                .len(),
+        ),
+        None => None,
+    };
+    let stats = PatchStats::new(&patch, source_len).expect("failed to parse the patch");
+
+    println!("window  target_offset  target_len  patch_len      add     copy(s)  copy(t)      run");
+    for (i, w) in stats.windows.iter().enumerate() {
+        print!(
+            "{:>6}  {:>13}  {:>10}  {:>9}",
+            i, w.target_offset, w.target_len, w.len
+        );
+        match &w.instructions {
+            Some(s) => println!(
+                "  {:>7}  {:>9}  {:>7}  {:>7}",
+                s.add_bytes, s.source_copy_bytes, s.target_copy_bytes, s.run_bytes
+            ),
+            None => println!("  (compressed)"),
+        }
     }
+
+    let s = &stats.instructions;
+    println!();
+    println!("patch:        {} bytes", stats.patch_len);
+    println!("target:       {} bytes", stats.target_len);
+    println!("ratio:        {:.2}%", stats.ratio() * 100.0);
+    println!("windows:      {}", stats.windows.len());
+    if stats.unread_windows > 0 {
+        println!(
+            "  of which {} compressed, left out of the totals below",
+            stats.unread_windows
+        );
+    }
+    println!(
+        "added:        {} bytes in {} instructions",
+        s.add_bytes, s.adds
+    );
+    println!(
+        "copied:       {} bytes from source, {} from target, in {} instructions",
+        s.source_copy_bytes, s.target_copy_bytes, s.copies
+    );
+    println!(
+        "run:          {} bytes in {} instructions",
+        s.run_bytes, s.runs
+    );
+    if let Some(share) = stats.source_use() {
+        println!(
+            "source used:  {} bytes ({:.2}%)",
+            stats.source_used,
+            share * 100.0
+        );
+    }
+    for (name, section) in &[
+        ("data", stats.data),
+        ("inst", stats.inst),
+        ("addr", stats.addr),
+    ] {
+        print_section(name, section);
+    }
+}
+
+fn print_section(name: &str, section: &Section) {
+    println!(
+        "{} section: {} bytes, {} stored, {} saved by secondary compression",
+        name,
+        section.len,
+        section.stored_len,
+        section.saved()
+    );
 }
 
 fn main() {
@@ -0,0 +1,37 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use xdelta3::provenance::provenance;
+use xdelta3::stats::PatchStats;
+use xdelta3::vcdiff::Patch;
+use xdelta3::{decode_with_limits, Limits};
+
+// The first byte tells how much of the rest is the source; the remainder is the patch.
+fuzz_target!(|data: &[u8]| {
+    let (src_len, data) = match data.split_first() {
+        Some((&n, data)) => (n as usize, data),
+        None => return,
+    };
+    let (src, patch) = data.split_at(src_len.min(data.len()));
+
+    let _ = xdelta3::decode(patch, src);
+    let limits = Limits::new()
+        .window_target_len(1 << 20)
+        .output_len(1 << 22);
+    let _ = decode_with_limits(patch, src, &limits);
+
+    if let Ok(parsed) = Patch::parse(patch) {
+        for window in parsed.windows() {
+            let window = match window {
+                Ok(window) => window,
+                Err(_) => break,
+            };
+            let _ = window.section_lens();
+            if let Ok(instructions) = window.instructions() {
+                instructions.for_each(drop);
+            }
+        }
+    }
+    let _ = PatchStats::new(patch, Some(src.len() as u64));
+    let _ = provenance(patch);
+});
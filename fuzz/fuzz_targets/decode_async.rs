@@ -0,0 +1,17 @@
+#![no_main]
+
+use futures::executor::block_on;
+use futures::io::{sink, Cursor};
+use libfuzzer_sys::fuzz_target;
+use xdelta3::stream::decode_async;
+
+// Laid out as for the `decode` target: a byte of source length, the source, then the patch.
+fuzz_target!(|data: &[u8]| {
+    let (src_len, data) = match data.split_first() {
+        Some((&n, data)) => (n as usize, data),
+        None => return,
+    };
+    let (src, patch) = data.split_at(src_len.min(data.len()));
+
+    let _ = block_on(decode_async(Cursor::new(patch), Cursor::new(src), sink()));
+});
@@ -0,0 +1,84 @@
+//! Glue between the allocation callbacks of `xd3_config` and Rust allocators.
+//!
+//! xdelta3 frees memory without telling us how large the allocation was, so every
+//! block handed out to C is prefixed by a small header recording its size.
+
+use std::alloc::{GlobalAlloc, Layout};
+use std::sync::Arc;
+
+use libc::c_void;
+
+use super::binding;
+
+/// Allocator used for the memory xdelta3 allocates internally.
+pub(crate) type Allocator = Arc<dyn GlobalAlloc + Send + Sync>;
+
+/// Alignment of the blocks handed out to C, matching what `malloc` guarantees.
+const ALIGN: usize = 16;
+/// Room reserved in front of every block to remember its size.
+const HEADER: usize = ALIGN;
+
+/// Forwards to the global allocator of the program.
+pub(crate) struct Global;
+
+unsafe impl GlobalAlloc for Global {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        std::alloc::alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        std::alloc::dealloc(ptr, layout)
+    }
+}
+
+/// Owns the allocator a stream has been configured with.
+///
+/// The allocator is boxed so that the pointer stored in `xd3_config::opaque` stays valid
+/// for as long as the `Hooks` are alive, which must be at least as long as the stream.
+pub(crate) struct Hooks(Box<Allocator>);
+
+impl Hooks {
+    pub(crate) fn new(allocator: Option<Allocator>) -> Self {
+        Self(Box::new(allocator.unwrap_or_else(|| Arc::new(Global))))
+    }
+
+    /// Points the allocation callbacks of `cfg` at this allocator.
+    pub(crate) fn install(&self, cfg: &mut binding::xd3_config) {
+        cfg.alloc = Some(xd3_alloc);
+        cfg.freef = Some(xd3_free);
+        cfg.opaque = &*self.0 as *const Allocator as *mut c_void;
+    }
+}
+
+unsafe extern "C" fn xd3_alloc(
+    opaque: *mut c_void,
+    items: binding::usize_t,
+    size: binding::usize_t,
+) -> *mut c_void {
+    let allocator = &*(opaque as *const Allocator);
+    let layout = match (items as usize)
+        .checked_mul(size as usize)
+        .and_then(|n| n.checked_add(HEADER))
+        .and_then(|n| Layout::from_size_align(n, ALIGN).ok())
+    {
+        Some(layout) => layout,
+        None => return std::ptr::null_mut(),
+    };
+
+    let ptr = allocator.alloc(layout);
+    if ptr.is_null() {
+        return std::ptr::null_mut();
+    }
+    (ptr as *mut usize).write(layout.size());
+    ptr.add(HEADER) as *mut c_void
+}
+
+unsafe extern "C" fn xd3_free(opaque: *mut c_void, address: *mut c_void) {
+    if address.is_null() {
+        return;
+    }
+    let allocator = &*(opaque as *const Allocator);
+    let ptr = (address as *mut u8).sub(HEADER);
+    let size = (ptr as *const usize).read();
+    allocator.dealloc(ptr, Layout::from_size_align_unchecked(size, ALIGN));
+}
@@ -0,0 +1,303 @@
+//! Patches between ZIP archives (JAR, APK...) that see through their compression.
+//!
+//! Deflate spreads any change over the rest of an entry, so two versions of an archive
+//! look unrelated to xdelta3. Here both archives are expanded first, with their deflated
+//! entries replaced by the inflated data, and the patch is made between the expanded
+//! archives. It also records how each entry of the target was compressed, so applying it
+//! compresses them again and rebuilds the archive byte for byte.
+//!
+//! An entry of the target is only expanded when compressing it again with miniz_oxide
+//! gives back the very same bytes; the others are diffed as they are stored. Archives
+//! written with zlib or another deflater seldom compress back the same, and when nothing is
+//! left to expand, or the target isn't a ZIP archive, the patch is a plain VCDIFF patch of
+//! the whole file. [`expanded_entries`] tells how many entries a patch expands.
+
+use miniz_oxide::deflate::compress_to_vec;
+use miniz_oxide::inflate::decompress_to_vec_with_limit;
+
+use log::{debug, warn};
+
+use super::error::Error;
+use super::vcdiff::{self, Cursor};
+use super::{try_decode, try_encode};
+
+/// Starts an archive patch, in place of the VCDIFF magic.
+const ARCHIVE_MAGIC: [u8; 8] = *b"XD3ZIPv1";
+
+const LOCAL_HEADER: u32 = 0x0403_4b50;
+const CENTRAL_HEADER: u32 = 0x0201_4b50;
+const END_OF_CENTRAL_DIRECTORY: u32 = 0x0605_4b50;
+const DEFLATE: u16 = 8;
+const ENCRYPTED: u16 = 0x0001;
+
+/// Compression levels of miniz_oxide, in the order they are tried.
+const LEVELS: [u8; 11] = [6, 9, 1, 2, 3, 4, 5, 7, 8, 10, 0];
+
+/// Deflated data of an entry of an archive.
+#[derive(Clone, Copy, Debug)]
+struct Entry {
+    start: usize,
+    stored_len: usize,
+    len: usize,
+}
+
+impl Entry {
+    fn end(&self) -> usize {
+        self.start + self.stored_len
+    }
+
+    fn stored<'a>(&self, zip: &'a [u8]) -> &'a [u8] {
+        &zip[self.start..self.end()]
+    }
+
+    /// Inflates the entry, if it holds what the archive says.
+    fn inflate(&self, zip: &[u8]) -> Option<Vec<u8>> {
+        decompress_to_vec_with_limit(self.stored(zip), self.len)
+            .ok()
+            .filter(|data| data.len() == self.len)
+    }
+}
+
+/// An entry to compress again when the archive is rebuilt.
+#[derive(Clone, Copy, Debug)]
+struct Recipe {
+    // bytes of the archive before the entry, since the previous one
+    gap: usize,
+    stored_len: usize,
+    len: usize,
+    level: u8,
+}
+
+/// Makes a patch from `src` to `input` as [`encode`](crate::encode) does, diffing the
+/// deflated entries of the two archives once inflated.
+///
+/// The patch is applied with [`decode_archive`]. Either archive may be something else
+/// than a ZIP archive, and the patch is then the same as `encode` makes.
+///
+/// ```
+/// extern crate xdelta3;
+/// use xdelta3::archive::{decode_archive, encode_archive};
+///
+/// fn main() {
+///     let (old, new) = (b"not an archive", b"not an archive either");
+///     let patch = encode_archive(new, old).unwrap();
+///     assert_eq!(decode_archive(&patch, old).unwrap(), new);
+/// }
+/// ```
+pub fn encode_archive(input: &[u8], src: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut expanded = Vec::new();
+    let mut recipes = Vec::new();
+    let mut pos = 0;
+    let entries = deflated_entries(input);
+    for &entry in &entries {
+        let data = match entry.inflate(input) {
+            Some(data) => data,
+            None => continue,
+        };
+        let stored = entry.stored(input);
+        let level = LEVELS
+            .iter()
+            .copied()
+            .find(|&level| compress_to_vec(&data, level) == stored);
+        if let Some(level) = level {
+            recipes.push(Recipe {
+                gap: entry.start - pos,
+                stored_len: entry.stored_len,
+                len: entry.len,
+                level,
+            });
+            expanded.extend_from_slice(&input[pos..entry.start]);
+            expanded.extend_from_slice(&data);
+            pos = entry.end();
+        }
+    }
+    debug!(
+        "expanding {} of {} deflated entries",
+        recipes.len(),
+        entries.len()
+    );
+    if recipes.is_empty() {
+        if !entries.is_empty() {
+            warn!(
+                "none of the {} deflated entries compress back the same, making a plain patch",
+                entries.len()
+            );
+        }
+        return try_encode(input, src);
+    }
+    expanded.extend_from_slice(&input[pos..]);
+
+    let mut out = ARCHIVE_MAGIC.to_vec();
+    vcdiff::write_varint(&mut out, input.len() as u64);
+    out.extend_from_slice(&vcdiff::adler32(input).to_be_bytes());
+    vcdiff::write_varint(&mut out, recipes.len() as u64);
+    for recipe in &recipes {
+        vcdiff::write_varint(&mut out, recipe.gap as u64);
+        vcdiff::write_varint(&mut out, recipe.stored_len as u64);
+        vcdiff::write_varint(&mut out, recipe.len as u64);
+        out.push(recipe.level);
+    }
+    out.extend_from_slice(&try_encode(&expanded, &expand(src))?);
+    Ok(out)
+}
+
+/// Returns how many deflated entries of the target a patch made by [`encode_archive`]
+/// expands, which is 0 for a plain VCDIFF patch.
+///
+/// Fails with [`Error::InvalidPatch`] if the header of an archive patch is cut short.
+pub fn expanded_entries(patch: &[u8]) -> Result<usize, Error> {
+    if !patch.starts_with(&ARCHIVE_MAGIC) {
+        return Ok(0);
+    }
+    let mut c = Cursor::new(patch, ARCHIVE_MAGIC.len());
+    c.varint()?;
+    c.bytes(4)?;
+    c.size()
+}
+
+/// Applies a patch made by [`encode_archive`] to `src`.
+///
+/// Fails with [`Error::InvalidPatch`] if the rebuilt archive doesn't match the one the patch
+/// was made for, which also happens if this build of miniz_oxide compresses differently
+/// from the one that made the patch.
+pub fn decode_archive(patch: &[u8], src: &[u8]) -> Result<Vec<u8>, Error> {
+    if !patch.starts_with(&ARCHIVE_MAGIC) {
+        return try_decode(patch, src);
+    }
+    let mut c = Cursor::new(patch, ARCHIVE_MAGIC.len());
+    let target_len = c.size()?;
+    let adler32 = c.bytes(4)?;
+    let adler32 = u32::from_be_bytes([adler32[0], adler32[1], adler32[2], adler32[3]]);
+    let mut recipes = Vec::new();
+    for _ in 0..c.varint()? {
+        let recipe = Recipe {
+            gap: c.size()?,
+            stored_len: c.size()?,
+            len: c.size()?,
+            level: c.byte()?,
+        };
+        if !LEVELS.contains(&recipe.level) {
+            return Err(Error::InvalidPatch("unknown compression level"));
+        }
+        recipes.push(recipe);
+    }
+    let expanded = try_decode(c.rest(), &expand(src))?;
+
+    let mut out = Vec::with_capacity(expanded.len());
+    let mut rest = &expanded[..];
+    for recipe in recipes {
+        out.extend_from_slice(take(&mut rest, recipe.gap)?);
+        let stored = compress_to_vec(take(&mut rest, recipe.len)?, recipe.level);
+        if stored.len() != recipe.stored_len {
+            return Err(Error::InvalidPatch("entry doesn't compress as recorded"));
+        }
+        out.extend_from_slice(&stored);
+    }
+    out.extend_from_slice(rest);
+    if out.len() != target_len || vcdiff::adler32(&out) != adler32 {
+        return Err(Error::InvalidPatch("rebuilt archive doesn't match"));
+    }
+    Ok(out)
+}
+
+fn take<'a>(data: &mut &'a [u8], n: usize) -> Result<&'a [u8], Error> {
+    if n > data.len() {
+        return Err(Error::InvalidPatch("entry past the end of the archive"));
+    }
+    let (head, tail) = data.split_at(n);
+    *data = tail;
+    Ok(head)
+}
+
+/// `zip` with all the deflated entries that can be inflated replaced by their content.
+fn expand(zip: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(zip.len());
+    let mut pos = 0;
+    for entry in deflated_entries(zip) {
+        if let Some(data) = entry.inflate(zip) {
+            out.extend_from_slice(&zip[pos..entry.start]);
+            out.extend_from_slice(&data);
+            pos = entry.end();
+        }
+    }
+    out.extend_from_slice(&zip[pos..]);
+    out
+}
+
+/// The deflated entries of `zip` in the order of their data, or none if it isn't a ZIP
+/// archive. ZIP64 archives and encrypted entries are left alone.
+fn deflated_entries(zip: &[u8]) -> Vec<Entry> {
+    let mut entries = read_central_directory(zip).unwrap_or_default();
+    entries.sort_by_key(|e| e.start);
+    // entries sharing their data would be expanded twice
+    let mut end = 0;
+    entries.retain(|e| {
+        let keep = e.start >= end;
+        if keep {
+            end = e.end();
+        }
+        keep
+    });
+    entries
+}
+
+fn read_central_directory(zip: &[u8]) -> Option<Vec<Entry>> {
+    // the end of central directory record, followed by a comment of up to 64 KiB
+    let last = zip.len().checked_sub(22)?;
+    let eocd = (last.saturating_sub(0xffff)..=last).rev().find(|&pos| {
+        u32_at(zip, pos) == Some(END_OF_CENTRAL_DIRECTORY)
+            && u16_at(zip, pos + 20).map(|n| pos + 22 + n as usize) == Some(zip.len())
+    })?;
+    let count = u16_at(zip, eocd + 10)?;
+    let mut pos = u32_at(zip, eocd + 16)? as usize;
+
+    let mut entries = Vec::new();
+    for _ in 0..count {
+        let header = zip.get(pos..)?;
+        if u32_at(header, 0)? != CENTRAL_HEADER {
+            return None;
+        }
+        let flags = u16_at(header, 8)?;
+        let method = u16_at(header, 10)?;
+        let stored_len = u32_at(header, 20)?;
+        let len = u32_at(header, 24)?;
+        let local = u32_at(header, 42)?;
+        pos += 46
+            + u16_at(header, 28)? as usize
+            + u16_at(header, 30)? as usize
+            + u16_at(header, 32)? as usize;
+        if method != DEFLATE
+            || flags & ENCRYPTED != 0
+            || [stored_len, len, local].contains(&u32::MAX)
+        {
+            continue;
+        }
+
+        let header = match zip.get(local as usize..) {
+            Some(header) if u32_at(header, 0) == Some(LOCAL_HEADER) => header,
+            _ => continue,
+        };
+        let start =
+            local as usize + 30 + u16_at(header, 26)? as usize + u16_at(header, 28)? as usize;
+        let end = start.checked_add(stored_len as usize);
+        if !matches!(end, Some(end) if end <= zip.len()) {
+            continue;
+        }
+        entries.push(Entry {
+            start,
+            stored_len: stored_len as usize,
+            len: len as usize,
+        });
+    }
+    Some(entries)
+}
+
+fn u16_at(data: &[u8], pos: usize) -> Option<u16> {
+    let b = data.get(pos..pos.checked_add(2)?)?;
+    Some(u16::from_le_bytes([b[0], b[1]]))
+}
+
+fn u32_at(data: &[u8], pos: usize) -> Option<u32> {
+    let b = data.get(pos..pos.checked_add(4)?)?;
+    Some(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
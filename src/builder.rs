@@ -0,0 +1,289 @@
+//! Writing VCDIFF patches from explicit instructions.
+//!
+//! When the change is already known, say a field rewritten at a fixed offset, a patch can
+//! be written directly instead of searching the source for matches.
+
+use super::error::Error;
+use super::vcdiff::{self, to_usize, AddressCache, VCD_ADLER32, VCD_SOURCE};
+
+#[derive(Clone, Debug)]
+enum Op {
+    Add(Vec<u8>),
+    Run { byte: u8, len: u64 },
+    CopySource { offset: u64, len: u64 },
+    CopyTarget { offset: u64, len: u64 },
+}
+
+impl Op {
+    fn len(&self) -> u64 {
+        match self {
+            Op::Add(data) => data.len() as u64,
+            Op::Run { len, .. } | Op::CopySource { len, .. } | Op::CopyTarget { len, .. } => *len,
+        }
+    }
+}
+
+/// Builder of a patch, one instruction at a time
+///
+/// Instructions append to the target in the order they are given, and are grouped into
+/// windows by [`end_window`](PatchBuilder::end_window). The result is a standard VCDIFF
+/// stream, which [`decode`](crate::decode) applies.
+///
+/// xdelta3 can't decode windows copying from the target of earlier windows, so
+/// [`copy_from_target`](PatchBuilder::copy_from_target) may only copy from the current
+/// window. Windows should also stay below 16 MiB of target, the largest xdelta3 accepts.
+///
+/// ```
+/// extern crate xdelta3;
+/// use xdelta3::builder::PatchBuilder;
+/// use xdelta3::decode;
+///
+/// fn main() {
+///     let source = b"version=1;name=firmware";
+///     let mut builder = PatchBuilder::new();
+///     builder.copy_from_source(0, 8).add(b"2").copy_from_source(9, 14);
+///     let patch = builder.finish().unwrap();
+///     assert_eq!(decode(&patch, source).unwrap(), b"version=2;name=firmware");
+/// }
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct PatchBuilder {
+    windows: Vec<Vec<Op>>,
+    current: Vec<Op>,
+}
+
+impl PatchBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends literal bytes.
+    pub fn add(&mut self, data: &[u8]) -> &mut Self {
+        if data.is_empty() {
+            return self;
+        }
+        match self.current.last_mut() {
+            Some(Op::Add(prev)) => prev.extend_from_slice(data),
+            _ => self.current.push(Op::Add(data.to_vec())),
+        }
+        self
+    }
+
+    /// Appends `len` bytes of the source, starting at `offset`.
+    pub fn copy_from_source(&mut self, offset: u64, len: u64) -> &mut Self {
+        self.push(Op::CopySource { offset, len })
+    }
+
+    /// Appends `len` bytes of the target, starting at `offset`, which must be inside the
+    /// current window and before the end of the target written so far. The copy may run
+    /// into the bytes it produces, repeating them.
+    pub fn copy_from_target(&mut self, offset: u64, len: u64) -> &mut Self {
+        self.push(Op::CopyTarget { offset, len })
+    }
+
+    /// Appends `len` copies of `byte`.
+    pub fn run(&mut self, byte: u8, len: u64) -> &mut Self {
+        self.push(Op::Run { byte, len })
+    }
+
+    /// Ends the current window; the next instructions go to a new one.
+    pub fn end_window(&mut self) -> &mut Self {
+        if !self.current.is_empty() {
+            self.windows.push(std::mem::take(&mut self.current));
+        }
+        self
+    }
+
+    /// Writes the patch.
+    pub fn finish(self) -> Result<Vec<u8>, Error> {
+        self.write(None)
+    }
+
+    /// Writes the patch with the Adler-32 of each window, which needs the source to
+    /// compute the target.
+    pub fn finish_with_adler32(self, source: &[u8]) -> Result<Vec<u8>, Error> {
+        self.write(Some(source))
+    }
+
+    fn push(&mut self, op: Op) -> &mut Self {
+        if op.len() > 0 {
+            self.current.push(op);
+        }
+        self
+    }
+
+    fn write(self, source: Option<&[u8]>) -> Result<Vec<u8>, Error> {
+        let mut out = Vec::new();
+        vcdiff::write_header(&mut out);
+        self.write_windows(&mut out, source)?;
+        Ok(out)
+    }
+
+    /// Appends the windows alone to `out`, to splice them into a patch written elsewhere.
+    pub(crate) fn write_windows(
+        mut self,
+        out: &mut Vec<u8>,
+        source: Option<&[u8]>,
+    ) -> Result<(), Error> {
+        self.end_window();
+        let mut target_offset = 0u64;
+        // the target so far, only kept for the checksums
+        let mut target = Vec::new();
+        for ops in &self.windows {
+            let adler32 = match source {
+                Some(source) => {
+                    let start = target.len();
+                    apply(ops, source, target_offset, &mut target)?;
+                    Some(vcdiff::adler32(&target[start..]))
+                }
+                None => None,
+            };
+            target_offset += write_window(out, ops, target_offset, adler32)?;
+        }
+        Ok(())
+    }
+}
+
+fn write_window(
+    out: &mut Vec<u8>,
+    ops: &[Op],
+    target_offset: u64,
+    adler32: Option<u32>,
+) -> Result<u64, Error> {
+    // the source segment spans all the source copies of the window
+    let mut segment: Option<(u64, u64)> = None;
+    for op in ops {
+        if let Op::CopySource { offset, len } = *op {
+            let end = offset
+                .checked_add(len)
+                .ok_or(Error::InvalidPatch("integer overflow"))?;
+            segment = Some(match segment {
+                Some((s, e)) => (s.min(offset), e.max(end)),
+                None => (offset, end),
+            });
+        }
+    }
+    let (position, segment_len) = segment.map_or((0, 0), |(s, e)| (s, e - s));
+
+    let mut data = Vec::new();
+    let mut inst = Vec::new();
+    let mut addr = Vec::new();
+    let mut cache = AddressCache::new();
+    // bytes of the window produced so far
+    let mut here = 0u64;
+    for op in ops {
+        let len = op.len();
+        match op {
+            Op::Add(bytes) => {
+                data.extend_from_slice(bytes);
+                write_inst(&mut inst, 1, len, 17);
+            }
+            Op::Run { byte, .. } => {
+                data.push(*byte);
+                write_inst(&mut inst, 0, len, 0);
+            }
+            Op::CopySource { offset, .. } | Op::CopyTarget { offset, .. } => {
+                let a = match op {
+                    Op::CopySource { .. } => offset - position,
+                    _ => {
+                        let start = offset
+                            .checked_sub(target_offset)
+                            .ok_or(Error::Unsupported("copy from an earlier window"))?;
+                        if start >= here {
+                            return Err(Error::InvalidPatch("copy from target not written yet"));
+                        }
+                        segment_len + start
+                    }
+                };
+                let mode = cache.encode(&mut addr, a, segment_len + here);
+                if (4..=18).contains(&len) {
+                    inst.push(19 + 16 * mode + (len - 3) as u8);
+                } else {
+                    inst.push(19 + 16 * mode);
+                    vcdiff::write_varint(&mut inst, len);
+                }
+            }
+        }
+        here += len;
+    }
+
+    let mut indicator = 0;
+    if segment.is_some() {
+        indicator |= VCD_SOURCE;
+    }
+    if adler32.is_some() {
+        indicator |= VCD_ADLER32;
+    }
+    let enc_len = vcdiff::varint_len(here)
+        + 1
+        + vcdiff::varint_len(data.len() as u64)
+        + vcdiff::varint_len(inst.len() as u64)
+        + vcdiff::varint_len(addr.len() as u64)
+        + adler32.map_or(0, |_| 4)
+        + data.len()
+        + inst.len()
+        + addr.len();
+
+    out.push(indicator);
+    if segment.is_some() {
+        vcdiff::write_varint(out, segment_len);
+        vcdiff::write_varint(out, position);
+    }
+    vcdiff::write_varint(out, enc_len as u64);
+    vcdiff::write_varint(out, here);
+    // delta indicator: no section is compressed
+    out.push(0);
+    vcdiff::write_varint(out, data.len() as u64);
+    vcdiff::write_varint(out, inst.len() as u64);
+    vcdiff::write_varint(out, addr.len() as u64);
+    if let Some(adler32) = adler32 {
+        out.extend_from_slice(&adler32.to_be_bytes());
+    }
+    out.extend_from_slice(&data);
+    out.extend_from_slice(&inst);
+    out.extend_from_slice(&addr);
+    Ok(here)
+}
+
+/// Writes an ADD or RUN instruction of `len` bytes, starting from `code` in the default
+/// code table, whose sizes go up to `max_size`.
+fn write_inst(inst: &mut Vec<u8>, code: u8, len: u64, max_size: u64) {
+    if (1..=max_size).contains(&len) {
+        inst.push(code + len as u8);
+    } else {
+        inst.push(code);
+        vcdiff::write_varint(inst, len);
+    }
+}
+
+/// Appends the target of a window to `target`, which holds the target before it.
+fn apply(ops: &[Op], source: &[u8], target_offset: u64, target: &mut Vec<u8>) -> Result<(), Error> {
+    for op in ops {
+        match op {
+            Op::Add(data) => target.extend_from_slice(data),
+            Op::Run { byte, len } => target.resize(target.len() + to_usize(*len)?, *byte),
+            Op::CopySource { offset, len } => {
+                let start = to_usize(*offset)?;
+                let bytes = start
+                    .checked_add(to_usize(*len)?)
+                    .and_then(|end| source.get(start..end))
+                    .ok_or(Error::InvalidPatch("copy past the end of the source"))?;
+                target.extend_from_slice(bytes);
+            }
+            Op::CopyTarget { offset, len } => {
+                if *offset < target_offset {
+                    return Err(Error::Unsupported("copy from an earlier window"));
+                }
+                if *offset >= target.len() as u64 {
+                    return Err(Error::InvalidPatch("copy from target not written yet"));
+                }
+                // byte by byte, since the copy may overlap what it produces
+                let start = *offset as usize;
+                for i in 0..to_usize(*len)? {
+                    target.push(target[start + i]);
+                }
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,78 @@
+use super::memory::{Mode, SliceStream};
+use super::vcdiff;
+
+/// Encoder for many small inputs against the same source
+///
+/// Every call to [`encode`](crate::encode) sets up an encoder of its own, allocates its hash
+/// tables and indexes the source again, which takes far longer than encoding a message of a
+/// few kilobytes. An `EncoderContext` keeps one encoder alive: each input is encoded as a
+/// window of its own, and the source is only indexed once.
+///
+/// Each returned patch stands on its own and is decoded with [`decode`](crate::decode).
+///
+/// ```
+/// extern crate xdelta3;
+/// use xdelta3::{decode, EncoderContext};
+///
+/// fn main() {
+///     let dictionary = b"GET /api/v1/users HTTP/1.1\r\nHost: example.com\r\n\r\n";
+///     let messages: [&[u8]; 2] = [
+///         b"GET /api/v1/users/1 HTTP/1.1\r\nHost: example.com\r\n\r\n",
+///         b"GET /api/v1/users/2 HTTP/1.1\r\nHost: example.com\r\n\r\n",
+///     ];
+///
+///     let mut context = EncoderContext::new(dictionary).unwrap();
+///     for message in &messages {
+///         let patch = context.encode(message).unwrap();
+///         assert_eq!(&decode(&patch, dictionary).unwrap()[..], *message);
+///     }
+/// }
+/// ```
+pub struct EncoderContext<'s> {
+    stream: SliceStream,
+    src: &'s [u8],
+    // file header, written by xdelta3 in front of the first window only
+    header: Option<Vec<u8>>,
+    failed: bool,
+}
+
+impl<'s> EncoderContext<'s> {
+    /// Creates an encoder for patches against `src`.
+    pub fn new(src: &'s [u8]) -> Option<Self> {
+        Some(Self {
+            stream: SliceStream::new(src.len())?,
+            src,
+            header: None,
+            failed: false,
+        })
+    }
+
+    /// Generates the patch turning the source into `input`, as [`encode`](crate::encode)
+    /// does.
+    ///
+    /// Once a call has failed, the context can't be used anymore and returns `None`.
+    pub fn encode(&mut self, input: &[u8]) -> Option<Vec<u8>> {
+        if self.failed {
+            return None;
+        }
+        let mut out = Vec::new();
+        let r = self.stream.run(Mode::Encode, input, self.src, &mut out);
+        self.failed = r.is_err();
+        r.ok()?;
+
+        match &self.header {
+            Some(header) => {
+                let mut patch = Vec::with_capacity(header.len() + out.len());
+                patch.extend_from_slice(header);
+                patch.extend_from_slice(&out);
+                Some(patch)
+            }
+            None => {
+                if let Ok(Some(header)) = vcdiff::parse_header_prefix(&out) {
+                    self.header = Some(out[..header.len].to_vec());
+                }
+                Some(out)
+            }
+        }
+    }
+}
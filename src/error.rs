@@ -0,0 +1,47 @@
+use std::fmt;
+use std::io;
+
+/// Errors reported by the parts of this crate that do more than a single call into xdelta3.
+#[derive(Debug)]
+pub enum Error {
+    /// Reading or writing one of the inputs or outputs failed.
+    Io(io::Error),
+    /// The patch is not a valid VCDIFF stream.
+    InvalidPatch(&'static str),
+    /// The patch uses a VCDIFF feature that is not implemented here.
+    Unsupported(&'static str),
+    /// xdelta3 failed to process the data.
+    Xdelta3(String),
+    /// The signature of a patch is missing or doesn't match.
+    BadSignature(&'static str),
+    /// The patch goes past one of the [`Limits`](crate::Limits) set for decoding it.
+    LimitExceeded(&'static str),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::InvalidPatch(msg) => write!(f, "invalid patch: {}", msg),
+            Error::Unsupported(msg) => write!(f, "unsupported patch: {}", msg),
+            Error::Xdelta3(msg) => write!(f, "xdelta3 error: {}", msg),
+            Error::BadSignature(msg) => write!(f, "bad signature: {}", msg),
+            Error::LimitExceeded(limit) => write!(f, "{} limit exceeded", limit),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
@@ -0,0 +1,238 @@
+//! Making and applying patches between files, replacing the output atomically.
+//!
+//! The output is written to a temporary file next to it, synced, and renamed over the old
+//! file only once it is complete, so readers see either the old file or the new one and an
+//! interrupted run leaves the old file in place.
+
+use std::ffi::OsString;
+use std::fs::{self, File, Metadata, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use super::error::Error;
+use super::inplace::sync_dir;
+use super::memory::Mode;
+use super::stream::{self, Config};
+
+/// Options of [`apply_file`] and [`create_patch_file`]
+///
+/// ```no_run
+/// extern crate xdelta3;
+/// use xdelta3::file::{apply_file, FileOptions};
+///
+/// fn main() {
+///     let options = FileOptions::new()
+///         .preserve_permissions(true)
+///         .preserve_mtime(true)
+///         .backup("app.bin.orig");
+///     apply_file("app.bin", "app.bin.vcdiff", "app.bin", &options).unwrap();
+/// }
+/// ```
+#[derive(Clone, Default)]
+pub struct FileOptions {
+    config: Config,
+    preserve_permissions: bool,
+    preserve_mtime: bool,
+    backup: Option<PathBuf>,
+}
+
+impl FileOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the options of the encoder or decoder.
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Gives the output the permissions of the file it replaces. For [`apply_file`], the
+    /// source is used when there is no such file.
+    pub fn preserve_permissions(mut self, preserve: bool) -> Self {
+        self.preserve_permissions = preserve;
+        self
+    }
+
+    /// Gives the output the modification time of the file it replaces. For
+    /// [`apply_file`], the source is used when there is no such file.
+    pub fn preserve_mtime(mut self, preserve: bool) -> Self {
+        self.preserve_mtime = preserve;
+        self
+    }
+
+    /// Keeps the file replaced by the output at `path`, replacing what is there.
+    pub fn backup<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.backup = Some(path.into());
+        self
+    }
+}
+
+/// Applies the patch at `patch` to the file at `source`, and atomically puts the result at
+/// `target`.
+///
+/// `target` may be `source` itself: the source is only replaced once the patch has been
+/// fully applied.
+pub fn apply_file<P1, P2, P3>(
+    source: P1,
+    patch: P2,
+    target: P3,
+    options: &FileOptions,
+) -> Result<(), Error>
+where
+    P1: AsRef<Path>,
+    P2: AsRef<Path>,
+    P3: AsRef<Path>,
+{
+    let (source, target) = (source.as_ref(), target.as_ref());
+    let src = File::open(source)?;
+    let patch = BufReader::new(File::open(patch)?);
+    let like = match metadata(target)? {
+        Some(metadata) => Some(metadata),
+        None => Some(src.metadata()?),
+    };
+    write_atomically(target, like, options, |out| {
+        stream::process(Mode::Decode, patch, src, out, &options.config)
+    })
+}
+
+/// Makes a patch from the file at `source` to the file at `target`, and atomically puts it
+/// at `patch`.
+pub fn create_patch_file<P1, P2, P3>(
+    source: P1,
+    target: P2,
+    patch: P3,
+    options: &FileOptions,
+) -> Result<(), Error>
+where
+    P1: AsRef<Path>,
+    P2: AsRef<Path>,
+    P3: AsRef<Path>,
+{
+    let patch = patch.as_ref();
+    let src = File::open(source)?;
+    let input = BufReader::new(File::open(target)?);
+    let like = metadata(patch)?;
+    write_atomically(patch, like, options, |out| {
+        stream::process(Mode::Encode, input, src, out, &options.config)
+    })
+}
+
+/// Metadata of the file at `path`, if there is one.
+fn metadata(path: &Path) -> io::Result<Option<Metadata>> {
+    match fs::metadata(path) {
+        Ok(metadata) => Ok(Some(metadata)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Writes a file at `path` with `write`, taking the permissions and modification time of
+/// `like` as `options` ask.
+fn write_atomically<F>(
+    path: &Path,
+    like: Option<Metadata>,
+    options: &FileOptions,
+    write: F,
+) -> Result<(), Error>
+where
+    F: FnOnce(&mut BufWriter<&File>) -> Result<(), Error>,
+{
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let temp = TempFile::create(dir, path)?;
+
+    let mut out = BufWriter::new(&temp.file);
+    write(&mut out)?;
+    out.flush()?;
+    drop(out);
+    if let Some(like) = like {
+        if options.preserve_permissions {
+            temp.file.set_permissions(like.permissions())?;
+        }
+        if options.preserve_mtime {
+            temp.file.set_modified(like.modified()?)?;
+        }
+    }
+    temp.file.sync_all()?;
+
+    if let Some(backup) = &options.backup {
+        if metadata(path)?.is_some() {
+            keep_backup(path, backup)?;
+        }
+    }
+    temp.persist(path)?;
+    sync_dir(dir)?;
+    Ok(())
+}
+
+/// Links `path` at `backup`, or copies it where links aren't supported.
+fn keep_backup(path: &Path, backup: &Path) -> io::Result<()> {
+    if let Err(e) = fs::remove_file(backup) {
+        if e.kind() != io::ErrorKind::NotFound {
+            return Err(e);
+        }
+    }
+    if fs::hard_link(path, backup).is_err() {
+        fs::copy(path, backup)?;
+        File::open(backup)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// A file in the directory of its final path, removed unless it is persisted.
+struct TempFile {
+    file: File,
+    path: PathBuf,
+    persisted: bool,
+}
+
+impl TempFile {
+    fn create(dir: &Path, path: &Path) -> io::Result<Self> {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+        let name = path.file_name().unwrap_or_default();
+        loop {
+            let mut temp_name = OsString::from(".");
+            temp_name.push(name);
+            temp_name.push(format!(
+                ".{}.{}.tmp",
+                std::process::id(),
+                COUNT.fetch_add(1, Ordering::Relaxed)
+            ));
+            let temp_path = dir.join(temp_name);
+            match OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&temp_path)
+            {
+                Ok(file) => {
+                    return Ok(Self {
+                        file,
+                        path: temp_path,
+                        persisted: false,
+                    })
+                }
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn persist(mut self, path: &Path) -> io::Result<()> {
+        fs::rename(&self.path, path)?;
+        self.persisted = true;
+        Ok(())
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if !self.persisted {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
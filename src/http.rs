@@ -0,0 +1,330 @@
+//! Source fetched from an HTTP server with range requests.
+//!
+//! Only the blocks xdelta3 asks for are downloaded, so a patch can be applied against a
+//! file kept on a server without fetching the parts of it the patch doesn't use.
+
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::io::{Error, ErrorKind, Result};
+use std::ops::Range;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use async_std::net::TcpStream;
+use futures_util::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use log::debug;
+
+use super::source::AsyncSource;
+
+const DEFAULT_CACHE_BLOCKS: usize = 16;
+/// Longest line of a response header, line break included.
+const MAX_LINE: u64 = 8 << 10;
+
+type Connection = BufReader<TcpStream>;
+type Fetch = Pin<Box<dyn Future<Output = (Option<Connection>, Result<Response>)> + Send>>;
+
+/// A source read from a URL, one range request per block.
+///
+/// Only plain `http://` URLs are supported, and the server must support range requests.
+/// The connection is kept open between requests, and the last blocks fetched are cached.
+///
+/// ```no_run
+/// use xdelta3::http::HttpSource;
+/// use xdelta3::stream::{decode_async_with_source, Config};
+///
+/// # async fn run(patch: &[u8]) -> Result<(), xdelta3::Error> {
+/// let source = HttpSource::new("http://example.com/app-1.0.bin")?.cache_blocks(32);
+/// let mut out = Vec::new();
+/// decode_async_with_source(patch, source, &mut out, &Config::new()).await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct HttpSource {
+    // the host as in the URL, and with the port to connect to
+    host: String,
+    addr: String,
+    path: String,
+    conn: Option<Connection>,
+    fetch: Option<(u64, Fetch)>,
+
+    cache: HashMap<u64, Vec<u8>>,
+    // least recently used first
+    order: VecDeque<u64>,
+    cache_blocks: usize,
+    size: Option<u64>,
+}
+
+impl HttpSource {
+    /// Creates a source reading `url`. No request is made until a block is needed.
+    pub fn new(url: &str) -> Result<Self> {
+        let rest = url.strip_prefix("http://").ok_or_else(|| {
+            Error::new(ErrorKind::InvalidInput, "only http:// URLs are supported")
+        })?;
+        let (host, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        if host.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidInput, "URL without a host"));
+        }
+        Ok(Self {
+            host: host.to_owned(),
+            addr: with_port(host)?,
+            path: path.to_owned(),
+            conn: None,
+            fetch: None,
+            cache: HashMap::new(),
+            order: VecDeque::new(),
+            cache_blocks: DEFAULT_CACHE_BLOCKS,
+            size: None,
+        })
+    }
+
+    /// Sets how many blocks are kept in memory, 16 by default.
+    pub fn cache_blocks(mut self, blocks: usize) -> Self {
+        self.cache_blocks = blocks.max(1);
+        self
+    }
+
+    fn insert(&mut self, blkno: u64, data: Vec<u8>) {
+        self.cache.insert(blkno, data);
+        self.order.push_back(blkno);
+        while self.order.len() > self.cache_blocks {
+            if let Some(old) = self.order.pop_front() {
+                self.cache.remove(&old);
+            }
+        }
+    }
+
+    fn touch(&mut self, blkno: u64) {
+        if let Some(i) = self.order.iter().position(|&b| b == blkno) {
+            self.order.remove(i);
+            self.order.push_back(blkno);
+        }
+    }
+}
+
+impl AsyncSource for HttpSource {
+    fn poll_block<'a>(
+        self: Pin<&'a mut Self>,
+        cx: &mut Context,
+        blkno: u64,
+        blksize: usize,
+    ) -> Poll<Result<&'a [u8]>> {
+        let this = self.get_mut();
+        if this.cache.contains_key(&blkno) {
+            this.touch(blkno);
+            return Poll::Ready(Ok(&this.cache[&blkno]));
+        }
+
+        let start = match blkno.checked_mul(blksize as u64) {
+            Some(start) if !matches!(this.size, Some(size) if start >= size) => start,
+            _ => return Poll::Ready(Ok(&[])),
+        };
+        if !matches!(&this.fetch, Some((b, _)) if *b == blkno) {
+            debug!("fetching block {}", blkno);
+            let fetch = fetch(
+                this.conn.take(),
+                this.addr.clone(),
+                this.host.clone(),
+                this.path.clone(),
+                start..start.saturating_add(blksize as u64),
+            );
+            this.fetch = Some((blkno, Box::pin(fetch)));
+        }
+        let (conn, response) = match this.fetch.as_mut() {
+            Some((_, fetch)) => match fetch.as_mut().poll(cx) {
+                Poll::Ready(r) => r,
+                Poll::Pending => return Poll::Pending,
+            },
+            None => unreachable!(),
+        };
+        this.fetch = None;
+        this.conn = conn;
+
+        let response = response?;
+        // the range starts past the end of the file
+        if response.status == 416 {
+            this.size = response.total.or(Some(start));
+            return Poll::Ready(Ok(&[]));
+        }
+        this.size = response.total.or(this.size);
+        this.insert(blkno, response.body);
+        Poll::Ready(Ok(&this.cache[&blkno]))
+    }
+
+    fn size(&self) -> Option<u64> {
+        self.size
+    }
+}
+
+/// `host` with the default port added if it has none. IPv6 addresses are in brackets.
+fn with_port(host: &str) -> Result<String> {
+    let malformed = || Error::new(ErrorKind::InvalidInput, "malformed host in URL");
+    let port = match host.strip_prefix('[') {
+        Some(rest) => &rest[rest.find(']').ok_or_else(malformed)? + 1..],
+        None => host.find(':').map_or("", |i| &host[i..]),
+    };
+    if port.is_empty() {
+        return Ok(format!("{}:80", host));
+    }
+    match port.strip_prefix(':').map(str::parse::<u16>) {
+        Some(Ok(_)) => Ok(host.to_owned()),
+        _ => Err(malformed()),
+    }
+}
+
+struct Response {
+    status: u16,
+    // length of the whole file, from `Content-Range`
+    total: Option<u64>,
+    body: Vec<u8>,
+    close: bool,
+}
+
+/// Requests `range` of the file, on `conn` if it is still open.
+async fn fetch(
+    conn: Option<Connection>,
+    addr: String,
+    host: String,
+    path: String,
+    range: Range<u64>,
+) -> (Option<Connection>, Result<Response>) {
+    let reused = conn.is_some();
+    let mut conn = match conn {
+        Some(conn) => conn,
+        None => match TcpStream::connect(&addr).await {
+            Ok(stream) => BufReader::new(stream),
+            Err(e) => return (None, Err(e)),
+        },
+    };
+    let mut response = request(&mut conn, &host, &path, range.clone()).await;
+    if response.is_err() && reused {
+        // the server may have closed the connection since the last request
+        debug!("retrying on a new connection");
+        conn = match TcpStream::connect(&addr).await {
+            Ok(stream) => BufReader::new(stream),
+            Err(e) => return (None, Err(e)),
+        };
+        response = request(&mut conn, &host, &path, range).await;
+    }
+    match response {
+        Ok(r) if !r.close => (Some(conn), Ok(r)),
+        r => (None, r),
+    }
+}
+
+async fn request(
+    conn: &mut Connection,
+    host: &str,
+    path: &str,
+    range: Range<u64>,
+) -> Result<Response> {
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nRange: bytes={}-{}\r\n\r\n",
+        path,
+        host,
+        range.start,
+        range.end - 1
+    );
+    conn.get_mut().write_all(request.as_bytes()).await?;
+
+    let mut line = String::new();
+    read_line(conn, &mut line).await?;
+    let status = line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "malformed HTTP status line"))?;
+    // anything else, a 200 for a server ignoring the range included, isn't worth reading
+    if status != 206 && status != 416 {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("unexpected HTTP status {}", status),
+        ));
+    }
+
+    let mut len = None;
+    let mut content_range = None;
+    let mut close = false;
+    loop {
+        read_line(conn, &mut line).await?;
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        let (name, value) = match header.split_once(':') {
+            Some((name, value)) => (name.trim(), value.trim()),
+            None => continue,
+        };
+        if name.eq_ignore_ascii_case("content-length") {
+            len = value.parse::<usize>().ok();
+        } else if name.eq_ignore_ascii_case("content-range") {
+            content_range = Some(parse_content_range(value));
+        } else if name.eq_ignore_ascii_case("connection") {
+            close = value.eq_ignore_ascii_case("close");
+        }
+    }
+    debug!(
+        "status={}, len={:?}, range={:?}",
+        status, len, content_range
+    );
+    let (start, total) = content_range.unwrap_or((None, None));
+    // bytes from elsewhere in the file would end up in the wrong place of the source
+    if status == 206 && start != Some(range.start) {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "HTTP response for another range than the one asked for",
+        ));
+    }
+
+    let len = match (len, status) {
+        (Some(len), _) => len,
+        (None, 416) => 0,
+        (None, _) => {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "HTTP response without a Content-Length",
+            ))
+        }
+    };
+    if len as u64 > range.end - range.start {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "HTTP response longer than the range asked for",
+        ));
+    }
+    let mut body = vec![0u8; len];
+    conn.read_exact(&mut body).await?;
+    Ok(Response {
+        status,
+        total,
+        body,
+        close,
+    })
+}
+
+/// Returns where the range of a `Content-Range` header starts, and the length of the file.
+fn parse_content_range(value: &str) -> (Option<u64>, Option<u64>) {
+    let (range, total) = match value.strip_prefix("bytes").and_then(|v| v.split_once('/')) {
+        Some(parts) => parts,
+        None => return (None, None),
+    };
+    let start = range.trim().split('-').next().and_then(|s| s.parse().ok());
+    (start, total.parse().ok())
+}
+
+async fn read_line(conn: &mut Connection, line: &mut String) -> Result<()> {
+    line.clear();
+    let n = (&mut *conn).take(MAX_LINE).read_line(line).await?;
+    if !line.ends_with('\n') {
+        if n as u64 == MAX_LINE {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "HTTP header line too long",
+            ));
+        }
+        return Err(ErrorKind::UnexpectedEof.into());
+    }
+    Ok(())
+}
@@ -0,0 +1,137 @@
+//! Random access to the target of a patch.
+//!
+//! The windows of a VCDIFF patch each produce a known range of the target, so a range of
+//! the target can be reconstructed by decoding only the windows overlapping it.
+
+use std::ops::Range;
+
+use super::error::Error;
+use super::vcdiff::{Patch, SegmentKind};
+
+/// Location of a window in the patch and in the target.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WindowEntry {
+    pub patch_offset: u64,
+    pub patch_len: u64,
+    pub target_offset: u64,
+    pub target_len: u64,
+    /// Whether the window copies from the target of earlier windows.
+    pub copies_target: bool,
+}
+
+/// Index of the windows of a patch.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PatchIndex {
+    pub(crate) header_len: u64,
+    pub(crate) windows: Vec<WindowEntry>,
+}
+
+impl PatchIndex {
+    /// Builds the index of a patch held in memory.
+    pub fn new(patch: &[u8]) -> Result<Self, Error> {
+        let patch = Patch::parse(patch)?;
+        let windows = patch
+            .windows()
+            .map(|w| {
+                w.map(|w| WindowEntry {
+                    patch_offset: w.offset as u64,
+                    patch_len: w.len as u64,
+                    target_offset: w.target_offset,
+                    target_len: w.target_len,
+                    copies_target: w.segment.map(|s| s.kind) == Some(SegmentKind::Target),
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            header_len: patch.header().len as u64,
+            windows,
+        })
+    }
+
+    #[cfg(feature = "stream")]
+    pub(crate) fn push(&mut self, patch_offset: u64, window: &super::vcdiff::WindowHeader) {
+        self.windows.push(WindowEntry {
+            patch_offset,
+            patch_len: window.len as u64,
+            target_offset: self.target_len(),
+            target_len: window.target_len,
+            copies_target: window.segment.map(|s| s.kind) == Some(SegmentKind::Target),
+        });
+    }
+
+    /// Number of bytes taken by the file header at the start of the patch.
+    pub fn header_len(&self) -> u64 {
+        self.header_len
+    }
+
+    pub fn windows(&self) -> &[WindowEntry] {
+        &self.windows
+    }
+
+    /// Total length of the target.
+    pub fn target_len(&self) -> u64 {
+        self.windows
+            .last()
+            .map(|w| w.target_offset + w.target_len)
+            .unwrap_or(0)
+    }
+
+    /// Indices of the windows needed to reconstruct `range` of the target.
+    ///
+    /// This is empty if the range is empty or past the end of the target.
+    pub fn windows_for(&self, range: Range<u64>) -> Range<usize> {
+        let range = range.start..range.end.min(self.target_len());
+        if range.start >= range.end {
+            return 0..0;
+        }
+        let first = self
+            .windows
+            .partition_point(|w| w.target_offset + w.target_len <= range.start);
+        let last = self
+            .windows
+            .partition_point(|w| w.target_offset < range.end);
+        // windows copying from the target need the windows before them
+        let first = if self.windows[first..last].iter().any(|w| w.copies_target) {
+            0
+        } else {
+            first
+        };
+        first..last
+    }
+
+    /// Byte range of the patch holding the windows in `windows`.
+    pub(crate) fn patch_range(&self, windows: Range<usize>) -> Range<u64> {
+        let first = &self.windows[windows.start];
+        let last = &self.windows[windows.end - 1];
+        first.patch_offset..last.patch_offset + last.patch_len
+    }
+}
+
+/// Decodes only `range` of the target of `patch`.
+///
+/// The returned data is shorter than the range if the range extends past the end of the
+/// target.
+///
+/// ```
+/// let patch = xdelta3::encode(&[1, 2, 3, 4, 5, 6, 7], &[1, 2, 4, 4, 7, 6, 7]).unwrap();
+/// let data = xdelta3::index::decode_range(&patch, &[1, 2, 4, 4, 7, 6, 7], 2..5).unwrap();
+/// assert_eq!(data, &[3, 4, 5]);
+/// ```
+pub fn decode_range(patch: &[u8], src: &[u8], range: Range<u64>) -> Result<Vec<u8>, Error> {
+    let index = PatchIndex::new(patch)?;
+    let windows = index.windows_for(range.clone());
+    if windows.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let body = index.patch_range(windows.clone());
+    let mut partial = patch[..index.header_len as usize].to_vec();
+    partial.extend_from_slice(&patch[body.start as usize..body.end as usize]);
+    let data = super::decode(&partial, src)
+        .ok_or_else(|| Error::Xdelta3("failed to decode windows".to_owned()))?;
+
+    let base = index.windows[windows.start].target_offset;
+    let start = (range.start.max(base) - base) as usize;
+    let end = ((range.end - base) as usize).min(data.len());
+    Ok(data[start.min(end)..end].to_vec())
+}
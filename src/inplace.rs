@@ -0,0 +1,599 @@
+//! Applying a patch over its own source, for devices that can't hold two copies of a file.
+//!
+//! Each window is decoded in memory and then written over the file. Before a window is
+//! written, the parts of the region it covers that later windows still copy from are saved
+//! in a journal, so no source byte is lost. The decoded window is journaled as well before
+//! being written, which lets an interrupted update resume where it stopped.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::Path;
+
+use super::error::Error;
+use super::vcdiff::{self, Instruction, Patch, SegmentKind, Window};
+use log::debug;
+
+const JOURNAL_MAGIC: &[u8; 8] = b"XD3INPL1";
+
+const TAG_PRESERVE: u8 = b'P';
+const TAG_WINDOW: u8 = b'W';
+const TAG_COMMIT: u8 = b'C';
+
+/// Largest target window decoded, as it is held in memory.
+const MAX_WINDOW: u64 = 1 << 26;
+
+/// Size of the chunks copied between the file and the journal.
+const CHUNK: u64 = 1 << 20;
+/// Length of the tag, the offset and the body length in front of the body of a record.
+const RECORD_HEAD: u64 = 17;
+
+/// Applies `patch` to the content of `file`, replacing it with the patched data.
+///
+/// `file` must be opened for reading and writing. `journal` is where the data needed to
+/// recover from an interruption is kept; it is created if needed and removed once the file
+/// has been fully patched. If a previous call was interrupted, calling this function again
+/// with the same file, patch and journal finishes the update.
+///
+/// The windows of the patch are decoded by this crate rather than by xdelta3, so patches
+/// using secondary compression are not supported.
+pub fn apply_in_place<P: AsRef<Path>>(
+    file: &mut File,
+    patch: &[u8],
+    journal: P,
+) -> Result<(), Error> {
+    let windows = Patch::parse(patch)?
+        .windows()
+        .collect::<Result<Vec<_>, _>>()?;
+    let reads = windows
+        .iter()
+        .map(source_reads)
+        .collect::<Result<Vec<_>, _>>()?;
+    let target_len = windows
+        .last()
+        .map(|w| w.target_offset + w.target_len)
+        .unwrap_or(0);
+
+    let source_len = file.metadata()?.len();
+    let mut journal = Journal::open(journal.as_ref(), patch, source_len)?;
+    if reads.iter().flatten().any(|r| r.end > journal.source_len) {
+        return Err(Error::InvalidPatch("copy past the end of the source"));
+    }
+
+    // the last window reading each preserved region
+    let mut last_reads: BTreeMap<u64, Option<usize>> = journal
+        .preserved
+        .iter()
+        .map(|(&start, body)| (start, last_reader(&reads, start, body.len)))
+        .collect();
+    let mut next = journal.committed.map(|i| i + 1).unwrap_or(0);
+    if let Some((index, body)) = journal.pending.take() {
+        if index == next && index < windows.len() {
+            debug!("replaying window {}", index);
+            file.seek(SeekFrom::Start(windows[index].target_offset))?;
+            copy_from(&mut journal.file, body, file, 1)?;
+            file.sync_data()?;
+            journal.commit(index)?;
+            next += 1;
+        }
+    }
+    if let Some(done) = next.checked_sub(1) {
+        release(&mut journal, &mut last_reads, done)?;
+    }
+
+    for (index, window) in windows.iter().enumerate().skip(next) {
+        let data = decode_window(file, &mut journal, window)?;
+        if let Some(adler32) = window.adler32 {
+            if vcdiff::adler32(&data) != adler32 {
+                return Err(Error::InvalidPatch("target window checksum mismatch"));
+            }
+        }
+
+        let region = window.target_offset..window.target_offset + window.target_len;
+        let mut conflicts: Vec<_> = reads[index + 1..]
+            .iter()
+            .flatten()
+            .filter_map(|r| intersect(r, &region))
+            .collect();
+        merge(&mut conflicts);
+        for r in conflicts {
+            if !journal.preserved.contains_key(&r.start) {
+                debug!("window {} preserves {:?}", index, r);
+                last_reads.insert(r.start, last_reader(&reads, r.start, r.end - r.start));
+                journal.preserve(file, r)?;
+            }
+        }
+
+        journal.window(index, &data)?;
+        write_at(file, window.target_offset, &data)?;
+        journal.commit(index)?;
+        release(&mut journal, &mut last_reads, index)?;
+    }
+
+    file.set_len(target_len)?;
+    file.sync_all()?;
+    journal.remove()
+}
+
+/// The last window reading from the `len` preserved bytes at `start`, if any does.
+fn last_reader(reads: &[Vec<Range<u64>>], start: u64, len: u64) -> Option<usize> {
+    let region = start..start + len;
+    reads
+        .iter()
+        .rposition(|r| r.iter().any(|r| intersect(r, &region).is_some()))
+}
+
+/// Lets go of the preserved regions no window after `index` reads.
+fn release(
+    journal: &mut Journal,
+    last_reads: &mut BTreeMap<u64, Option<usize>>,
+    index: usize,
+) -> Result<(), Error> {
+    last_reads.retain(|start, last| {
+        let keep = matches!(*last, Some(last) if last > index);
+        if !keep {
+            journal.preserved.remove(start);
+        }
+        keep
+    });
+    journal.compact()
+}
+
+/// Ranges of the source read by the copies of `window`, sorted and merged.
+fn source_reads(window: &Window) -> Result<Vec<Range<u64>>, Error> {
+    let segment = match window.segment {
+        Some(s) if s.kind == SegmentKind::Source => Some(s),
+        _ => None,
+    };
+    let mut reads = Vec::new();
+    for inst in window.instructions()? {
+        if let (Some(s), Instruction::Copy { addr, len }) = (segment, inst?) {
+            if addr < s.len {
+                let end = addr.saturating_add(len).min(s.len);
+                reads.push(s.position + addr..s.position + end);
+            }
+        }
+    }
+    merge(&mut reads);
+    Ok(reads)
+}
+
+fn decode_window(
+    file: &mut File,
+    journal: &mut Journal,
+    window: &Window,
+) -> Result<Vec<u8>, Error> {
+    if window.target_len > MAX_WINDOW {
+        return Err(Error::Unsupported(
+            "target window too large to patch in place",
+        ));
+    }
+    let segment_len = window.segment_len();
+    let mut out = Vec::with_capacity(window.target_len as usize);
+    // the instructions never produce more than `target_len` bytes
+    for inst in window.instructions()? {
+        match inst? {
+            Instruction::Add(data) => out.extend_from_slice(data),
+            Instruction::Run { byte, len } => out.resize(out.len() + len as usize, byte),
+            Instruction::Copy { mut addr, mut len } => {
+                if let Some(s) = window.segment.filter(|_| addr < segment_len) {
+                    let n = len.min(segment_len - addr);
+                    let start = out.len();
+                    out.resize(start + n as usize, 0);
+                    match s.kind {
+                        SegmentKind::Source => {
+                            read_source(file, journal, s.position + addr, &mut out[start..])?
+                        }
+                        // earlier windows already hold their final content
+                        SegmentKind::Target => read_at(file, s.position + addr, &mut out[start..])?,
+                    }
+                    addr += n;
+                    len -= n;
+                }
+                // copies from the window itself may overlap the bytes they produce
+                let start = (addr - segment_len) as usize;
+                for i in 0..len as usize {
+                    let b = out[start + i];
+                    out.push(b);
+                }
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// Reads original source bytes, which may have been moved to the journal.
+fn read_source(
+    file: &mut File,
+    journal: &mut Journal,
+    pos: u64,
+    buf: &mut [u8],
+) -> Result<(), Error> {
+    read_at(file, pos, buf)?;
+    let want = pos..pos + buf.len() as u64;
+    for (&start, body) in journal.preserved.range(..want.end) {
+        if let Some(r) = intersect(&want, &(start..start + body.len)) {
+            let buf = &mut buf[(r.start - pos) as usize..(r.end - pos) as usize];
+            read_at(&mut journal.file, body.at + (r.start - start), buf)?;
+        }
+    }
+    Ok(())
+}
+
+fn read_at(file: &mut File, pos: u64, buf: &mut [u8]) -> Result<(), Error> {
+    file.seek(SeekFrom::Start(pos))?;
+    file.read_exact(buf)?;
+    Ok(())
+}
+
+fn write_at(file: &mut File, pos: u64, buf: &[u8]) -> Result<(), Error> {
+    file.seek(SeekFrom::Start(pos))?;
+    file.write_all(buf)?;
+    file.sync_data()?;
+    Ok(())
+}
+
+fn intersect(a: &Range<u64>, b: &Range<u64>) -> Option<Range<u64>> {
+    let r = a.start.max(b.start)..a.end.min(b.end);
+    if r.start < r.end {
+        Some(r)
+    } else {
+        None
+    }
+}
+
+fn merge(ranges: &mut Vec<Range<u64>>) {
+    ranges.sort_by_key(|r| r.start);
+    let mut merged: Vec<Range<u64>> = Vec::with_capacity(ranges.len());
+    for r in ranges.drain(..) {
+        match merged.last_mut() {
+            Some(last) if r.start <= last.end => last.end = last.end.max(r.end),
+            _ => merged.push(r),
+        }
+    }
+    *ranges = merged;
+}
+
+/// Append-only log of what has been done to the file.
+///
+/// The journal starts with the identity of the patch and the original length of the file,
+/// followed by records, each ending with its Adler-32 checksum:
+/// - `P offset len data`: original source bytes that are about to be overwritten,
+/// - `W index len data`: decoded content of a window that is about to be written,
+/// - `C index`: the window has been written.
+///
+/// Every record is synced before the file is modified, so a torn record at the end can
+/// simply be dropped. Once most of the journal is records no longer needed, it is
+/// replaced by one holding only the preserved regions still to be read.
+///
+/// Record bodies stay in the journal, which is read as it is needed.
+struct Journal<'a> {
+    file: File,
+    path: &'a Path,
+    // identity and original length of the file
+    header: Vec<u8>,
+    len: u64,
+    source_len: u64,
+    preserved: BTreeMap<u64, Body>,
+    committed: Option<usize>,
+    pending: Option<(usize, Body)>,
+}
+
+/// Where the body of a record is in the journal.
+#[derive(Clone, Copy, Debug)]
+struct Body {
+    at: u64,
+    len: u64,
+}
+
+impl<'a> Journal<'a> {
+    fn open(path: &'a Path, patch: &[u8], source_len: u64) -> Result<Self, Error> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+
+        let mut identity = JOURNAL_MAGIC.to_vec();
+        identity.extend_from_slice(&(patch.len() as u64).to_be_bytes());
+        identity.extend_from_slice(&vcdiff::adler32(patch).to_be_bytes());
+
+        let mut journal = Self {
+            file,
+            path,
+            header: Vec::new(),
+            len: 0,
+            source_len,
+            preserved: BTreeMap::new(),
+            committed: None,
+            pending: None,
+        };
+
+        let mut reader = BufReader::new(&journal.file);
+        let mut header = vec![0u8; identity.len() + 8];
+        if !read_full(&mut reader, &mut header)? {
+            drop(reader);
+            journal.file.set_len(0)?;
+            identity.extend_from_slice(&source_len.to_be_bytes());
+            journal.append(&identity)?;
+            // the journal has to be found again before the file is modified
+            sync_dir(parent(path))?;
+            journal.header = identity;
+            return Ok(journal);
+        }
+        if !header.starts_with(&identity) {
+            return Err(Error::InvalidPatch("journal belongs to another patch"));
+        }
+
+        let mut len = [0u8; 8];
+        len.copy_from_slice(&header[identity.len()..]);
+        journal.source_len = u64::from_be_bytes(len);
+        let mut valid = header.len() as u64;
+        while let Some(record) = read_record(&mut reader, valid)? {
+            let offset = record.offset;
+            match (record.tag, record.body) {
+                (TAG_PRESERVE, Some(body)) => {
+                    journal.preserved.insert(offset, body);
+                }
+                (TAG_WINDOW, Some(body)) => journal.pending = Some((offset as usize, body)),
+                (TAG_COMMIT, _) => {
+                    journal.committed = Some(offset as usize);
+                    journal.pending = None;
+                }
+                _ => break,
+            }
+            valid = record.end;
+        }
+        drop(reader);
+        debug!(
+            "journal: committed={:?}, pending={}",
+            journal.committed,
+            journal.pending.is_some()
+        );
+
+        journal.header = header;
+        journal.file.set_len(valid)?;
+        journal.len = valid;
+        Ok(journal)
+    }
+
+    /// Saves `range` of `file` before it is overwritten.
+    fn preserve(&mut self, file: &mut File, range: Range<u64>) -> Result<(), Error> {
+        let body = Body {
+            at: range.start,
+            len: range.end - range.start,
+        };
+        self.file.seek(SeekFrom::Start(self.len))?;
+        copy_record(&mut self.file, TAG_PRESERVE, range.start, file, body)?;
+        self.file.sync_data()?;
+        self.preserved.insert(
+            range.start,
+            Body {
+                at: self.len + RECORD_HEAD,
+                len: body.len,
+            },
+        );
+        self.len += RECORD_HEAD + body.len + 4;
+        Ok(())
+    }
+
+    fn window(&mut self, index: usize, data: &[u8]) -> Result<(), Error> {
+        self.record(TAG_WINDOW, index as u64, Some(data))
+    }
+
+    fn commit(&mut self, index: usize) -> Result<(), Error> {
+        self.committed = Some(index);
+        self.record(TAG_COMMIT, index as u64, None)
+    }
+
+    fn record(&mut self, tag: u8, offset: u64, body: Option<&[u8]>) -> Result<(), Error> {
+        let mut rec = Vec::new();
+        write_record(&mut rec, tag, offset, body);
+        self.append(&rec)
+    }
+
+    fn append(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.file.seek(SeekFrom::Start(self.len))?;
+        self.file.write_all(data)?;
+        self.file.sync_data()?;
+        self.len += data.len() as u64;
+        Ok(())
+    }
+
+    /// Replaces the journal with the header, the preserved regions and the last commit,
+    /// if that makes it less than half as long.
+    fn compact(&mut self) -> Result<(), Error> {
+        let index = match self.committed {
+            Some(index) => index,
+            None => return Ok(()),
+        };
+        let mut commit = Vec::new();
+        write_record(&mut commit, TAG_COMMIT, index as u64, None);
+        let preserved: u64 = self
+            .preserved
+            .values()
+            .map(|b| RECORD_HEAD + b.len + 4)
+            .sum();
+        let len = (self.header.len() + commit.len()) as u64 + preserved;
+        if self.len < 2 * len {
+            return Ok(());
+        }
+        debug!("compacting the journal from {} to {} bytes", self.len, len);
+
+        // the new journal only takes the place of the old one once it is complete
+        let mut temp = self.path.as_os_str().to_owned();
+        temp.push(".tmp");
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&temp)?;
+        file.write_all(&self.header)?;
+        let mut pos = self.header.len() as u64;
+        let mut moved = BTreeMap::new();
+        for (&offset, &body) in &self.preserved {
+            copy_record(&mut file, TAG_PRESERVE, offset, &mut self.file, body)?;
+            let at = pos + RECORD_HEAD;
+            moved.insert(offset, Body { at, len: body.len });
+            pos = at + body.len + 4;
+        }
+        file.write_all(&commit)?;
+        file.sync_data()?;
+        // closed first, for systems that don't replace open files
+        drop(std::mem::replace(&mut self.file, file));
+        fs::rename(&temp, self.path)?;
+        sync_dir(parent(self.path))?;
+        self.preserved = moved;
+        self.len = len;
+        Ok(())
+    }
+
+    fn remove(self) -> Result<(), Error> {
+        drop(self.file);
+        fs::remove_file(self.path)?;
+        Ok(())
+    }
+}
+
+/// The directory `path` is in.
+fn parent(path: &Path) -> &Path {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    }
+}
+
+/// Makes the creation, removal or renaming of a file in `dir` durable.
+#[cfg(unix)]
+pub(crate) fn sync_dir(dir: &Path) -> std::io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+pub(crate) fn sync_dir(_dir: &Path) -> std::io::Result<()> {
+    Ok(())
+}
+
+fn write_record(out: &mut Vec<u8>, tag: u8, offset: u64, body: Option<&[u8]>) {
+    let start = out.len();
+    out.push(tag);
+    out.extend_from_slice(&offset.to_be_bytes());
+    if let Some(body) = body {
+        out.extend_from_slice(&(body.len() as u64).to_be_bytes());
+        out.extend_from_slice(body);
+    }
+    let checksum = vcdiff::adler32(&out[start..]);
+    out.extend_from_slice(&checksum.to_be_bytes());
+}
+
+/// Writes a record whose body is `body` of `from`.
+fn copy_record<W: Write>(
+    out: &mut W,
+    tag: u8,
+    offset: u64,
+    from: &mut File,
+    body: Body,
+) -> Result<(), Error> {
+    let mut head = vec![tag];
+    head.extend_from_slice(&offset.to_be_bytes());
+    head.extend_from_slice(&body.len.to_be_bytes());
+    out.write_all(&head)?;
+    let checksum = copy_from(from, body, out, vcdiff::adler32(&head))?;
+    out.write_all(&checksum.to_be_bytes())?;
+    Ok(())
+}
+
+/// Copies `body` of `from` to `out`, returning the Adler-32 of what `adler` is the checksum
+/// of followed by the bytes copied.
+fn copy_from<W: Write>(
+    from: &mut File,
+    body: Body,
+    out: &mut W,
+    mut adler: u32,
+) -> Result<u32, Error> {
+    from.seek(SeekFrom::Start(body.at))?;
+    let mut buf = vec![0u8; body.len.min(CHUNK) as usize];
+    let mut left = body.len;
+    while left > 0 {
+        let chunk = &mut buf[..left.min(CHUNK) as usize];
+        from.read_exact(chunk)?;
+        adler = vcdiff::adler32_update(adler, chunk);
+        out.write_all(chunk)?;
+        left -= chunk.len() as u64;
+    }
+    Ok(adler)
+}
+
+/// Fills `buf`, returning `false` if the end of `reader` comes first.
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<bool, Error> {
+    match reader.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// A record read back from the journal.
+struct Record {
+    tag: u8,
+    offset: u64,
+    body: Option<Body>,
+    // position of the next record
+    end: u64,
+}
+
+/// Reads the record at `pos` of the journal. Returns `None` at the end of the journal or at
+/// a torn record.
+fn read_record<R: Read>(reader: &mut R, pos: u64) -> Result<Option<Record>, Error> {
+    let mut head = [0u8; RECORD_HEAD as usize];
+    if !read_full(reader, &mut head[..9])? {
+        return Ok(None);
+    }
+    let mut offset = [0u8; 8];
+    offset.copy_from_slice(&head[1..9]);
+    let (head, body) = match head[0] {
+        TAG_PRESERVE | TAG_WINDOW => {
+            if !read_full(reader, &mut head[9..])? {
+                return Ok(None);
+            }
+            let mut len = [0u8; 8];
+            len.copy_from_slice(&head[9..]);
+            let body = Body {
+                at: pos + RECORD_HEAD,
+                len: u64::from_be_bytes(len),
+            };
+            (&head[..], Some(body))
+        }
+        _ => (&head[..9], None),
+    };
+
+    // the body is only checked here, and read again when it is needed
+    let mut adler = vcdiff::adler32(head);
+    let mut end = pos + head.len() as u64;
+    if let Some(body) = body {
+        let mut buf = vec![0u8; body.len.min(CHUNK) as usize];
+        let mut left = body.len;
+        while left > 0 {
+            let chunk = &mut buf[..left.min(CHUNK) as usize];
+            if !read_full(reader, chunk)? {
+                return Ok(None);
+            }
+            adler = vcdiff::adler32_update(adler, chunk);
+            left -= chunk.len() as u64;
+        }
+        end += body.len;
+    }
+    let mut checksum = [0u8; 4];
+    if !read_full(reader, &mut checksum)? || checksum != adler.to_be_bytes() {
+        return Ok(None);
+    }
+    Ok(Some(Record {
+        tag: head[0],
+        offset: u64::from_be_bytes(offset),
+        body,
+        end: end + 4,
+    }))
+}
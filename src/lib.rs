@@ -15,8 +15,40 @@ extern crate libc;
 
 use libc::c_uint;
 
+#[cfg(feature = "stream")]
+mod alloc;
+#[cfg(feature = "archive")]
+pub mod archive;
+pub mod builder;
+mod context;
+mod error;
+#[cfg(feature = "stream")]
+pub mod file;
+#[cfg(feature = "http")]
+pub mod http;
+pub mod index;
+pub mod inplace;
+mod limits;
+mod memory;
+pub mod provenance;
+mod reverse;
+#[cfg(feature = "signing")]
+pub mod signing;
+#[cfg(feature = "stream")]
+pub mod source;
+pub mod sparse;
+pub mod stats;
 #[cfg(feature = "stream")]
 pub mod stream;
+pub mod vcdiff;
+mod verify;
+#[cfg(feature = "stream")]
+mod worker;
+
+pub use context::EncoderContext;
+pub use error::Error;
+pub use limits::{decode_with_limits, Limits};
+pub use verify::{verify, Verification};
 
 #[allow(dead_code)]
 mod binding {
@@ -54,11 +86,24 @@ mod binding {
 /// You might notice the generated patch data is larger than both orginal data and the updated data.
 /// But don't worry, if your data is large enough and kind of similar between each other (usually the case
 /// for software updates or ROM patches), the patch data should be only a fraction of your updated file.
+///
+/// Data larger than 4 GiB is supported; it is encoded one window at a time.
 pub fn encode(input: &[u8], src: &[u8]) -> Option<Vec<u8>> {
+    encode_up_to(input, src, memory::MAX_LEN)
+}
+
+/// [`encode`], going one window at a time for data longer than `max_len`.
+fn encode_up_to(input: &[u8], src: &[u8], max_len: u64) -> Option<Vec<u8>> {
+    let input_len = input.len() as u64;
+    let src_len = src.len() as u64;
+    if input_len > max_len || src_len > max_len {
+        return memory::process(memory::Mode::Encode, input, src);
+    }
     unsafe {
-        let input_len = input.len() as c_uint;
-        let src_len = src.len() as c_uint;
-        let estimated_out_len = (input_len + src_len) * 2;
+        let input_len = input_len as c_uint;
+        let src_len = src_len as c_uint;
+        let estimated_out_len =
+            ((input_len as u64 + src_len as u64) * 2).min(memory::MAX_LEN) as c_uint;
         let mut avail_output = 0 as c_uint;
         let mut output = Vec::with_capacity(estimated_out_len as usize);
         let result = binding::xd3_encode_memory(
@@ -74,6 +119,9 @@ pub fn encode(input: &[u8], src: &[u8]) -> Option<Vec<u8>> {
         if result == 0 {
             output.set_len(avail_output as usize);
             Some(output)
+        } else if result == libc::ENOSPC {
+            // the output doesn't fit in the estimate
+            memory::process(memory::Mode::Encode, input, src)
         } else {
             None
         }
@@ -103,11 +151,24 @@ pub fn encode(input: &[u8], src: &[u8]) -> Option<Vec<u8>> {
 ///     assert_eq!(result.unwrap().as_slice(), &[1, 2, 3, 4, 5, 6, 7]);
 /// }
 /// ```
+///
+/// As with [`encode`], the source and the patched data may be larger than 4 GiB.
 pub fn decode(input: &[u8], src: &[u8]) -> Option<Vec<u8>> {
+    decode_up_to(input, src, memory::MAX_LEN)
+}
+
+/// [`decode`], going one window at a time for data longer than `max_len`.
+fn decode_up_to(input: &[u8], src: &[u8], max_len: u64) -> Option<Vec<u8>> {
+    let input_len = input.len() as u64;
+    let src_len = src.len() as u64;
+    if input_len > max_len || src_len > max_len {
+        return memory::process(memory::Mode::Decode, input, src);
+    }
     unsafe {
-        let input_len = input.len() as c_uint;
-        let src_len = src.len() as c_uint;
-        let estimated_out_len = (input_len + src_len) * 2;
+        let input_len = input_len as c_uint;
+        let src_len = src_len as c_uint;
+        let estimated_out_len =
+            ((input_len as u64 + src_len as u64) * 2).min(memory::MAX_LEN) as c_uint;
         let mut avail_output = 0 as c_uint;
         let mut output = Vec::with_capacity(estimated_out_len as usize);
         let result = binding::xd3_decode_memory(
@@ -123,8 +184,73 @@ pub fn decode(input: &[u8], src: &[u8]) -> Option<Vec<u8>> {
         if result == 0 {
             output.set_len(avail_output as usize);
             Some(output)
+        } else if result == libc::ENOSPC {
+            // the output doesn't fit in the estimate
+            memory::process(memory::Mode::Decode, input, src)
         } else {
             None
         }
     }
 }
+
+/// [`encode`], for the parts of the crate that report an [`Error`].
+pub(crate) fn try_encode(input: &[u8], src: &[u8]) -> Result<Vec<u8>, Error> {
+    encode(input, src).ok_or_else(|| Error::Xdelta3("encoding failed".to_owned()))
+}
+
+/// [`decode`], for the parts of the crate that report an [`Error`].
+#[cfg(any(feature = "archive", feature = "signing"))]
+pub(crate) fn try_decode(input: &[u8], src: &[u8]) -> Result<Vec<u8>, Error> {
+    decode(input, src).ok_or_else(|| Error::Xdelta3("decoding failed".to_owned()))
+}
+
+/// Function to generate a patch and its reverse at once
+///
+/// Returns a tuple holding the patch turning `src` into `input` (the same as [`encode`] would
+/// return) and the patch turning `input` back into `src`, which can be used to undo an update.
+///
+/// ```
+/// extern crate xdelta3;
+/// use xdelta3::{decode, encode_pair};
+///
+/// fn main() {
+///     let (forward, reverse) = encode_pair(&[1, 2, 3, 4, 5, 6, 7], &[1, 2, 4, 4, 7, 6, 7]).unwrap();
+///     assert_eq!(decode(&forward, &[1, 2, 4, 4, 7, 6, 7]).unwrap(), &[1, 2, 3, 4, 5, 6, 7]);
+///     assert_eq!(decode(&reverse, &[1, 2, 3, 4, 5, 6, 7]).unwrap(), &[1, 2, 4, 4, 7, 6, 7]);
+/// }
+/// ```
+///
+/// The reverse patch is built from the forward one: what the forward patch copies from `src`
+/// is copied back from `input`, so only the bytes of `src` it doesn't copy are searched for
+/// in `input`.
+pub fn encode_pair(input: &[u8], src: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+    let forward = encode(input, src)?;
+    let reverse = reverse::encode_reverse(&forward, input, src, try_encode)
+        .ok()
+        .or_else(|| encode(src, input))?;
+    Some((forward, reverse))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn windowed_above_max_len() {
+        // a few MiB, going through the windows of `memory` as data above 4 GiB would
+        let src: Vec<u8> = (0..6u32 << 20)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8)
+            .collect();
+        let mut input = src[1 << 20..].to_vec();
+        input.extend_from_slice(&src[..1 << 20]);
+        input[3 << 20] ^= 1;
+
+        let patch = encode_up_to(&input, &src, 1 << 20).expect("failed to encode");
+        assert!(patch.len() < 1 << 12, "{}", patch.len());
+        assert_eq!(
+            decode_up_to(&patch, &src, 1 << 20).expect("failed to decode"),
+            input
+        );
+        assert_eq!(decode(&patch, &src).expect("failed to decode"), input);
+    }
+}
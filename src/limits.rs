@@ -0,0 +1,290 @@
+//! Limits on what a patch may ask of the decoder, for patches from untrusted sources.
+//!
+//! A patch of a few bytes can declare windows of gigabytes, or expand into far more output
+//! than the application is ready to take. The limits are checked against the headers of the
+//! windows as the patch is read, before xdelta3 gets to decode them.
+
+use super::error::Error;
+use super::memory::{Mode, SliceStream};
+use super::vcdiff::{self, VCD_INSTCOMP};
+
+/// Limits checked while decoding
+///
+/// All limits are off by default.
+///
+/// ```
+/// extern crate xdelta3;
+/// use xdelta3::{decode_with_limits, Error, Limits};
+///
+/// fn main() {
+///     let patch = [214, 195, 196, 0, 0, 0, 13, 7, 0, 7, 1, 0, 1, 2, 3, 4, 5, 6, 7, 8];
+///     let limits = Limits::new().output_len(4);
+///     match decode_with_limits(&patch, &[], &limits) {
+///         Err(Error::LimitExceeded(_)) => {}
+///         _ => panic!("the patch produces 7 bytes"),
+///     }
+/// }
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Limits {
+    window_target_len: u64,
+    window_len: u64,
+    output_len: u64,
+    instructions: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            window_target_len: u64::MAX,
+            window_len: u64::MAX,
+            output_len: u64::MAX,
+            instructions: u64::MAX,
+        }
+    }
+}
+
+impl Limits {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets how many bytes of target a single window may produce. xdelta3 holds the target
+    /// of a window in memory while decoding it.
+    pub fn window_target_len(mut self, len: u64) -> Self {
+        self.window_target_len = len;
+        self
+    }
+
+    /// Sets how many bytes a single window may take in the patch. xdelta3 holds the
+    /// sections of a window in memory while decoding it.
+    pub fn window_len(mut self, len: u64) -> Self {
+        self.window_len = len;
+        self
+    }
+
+    /// Sets how many bytes of target the whole patch may produce.
+    pub fn output_len(mut self, len: u64) -> Self {
+        self.output_len = len;
+        self
+    }
+
+    /// Sets how many instructions the whole patch may hold.
+    ///
+    /// The instructions of windows with a compressed instruction section can't be told
+    /// apart without decompressing it; such a section counts as two instructions per byte
+    /// of its decompressed length, as many as it could hold.
+    pub fn instructions(mut self, count: u64) -> Self {
+        self.instructions = count;
+        self
+    }
+
+    #[cfg(feature = "stream")]
+    pub(crate) fn is_unlimited(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Stage {
+    FileHeader,
+    WindowHeader,
+    /// Inside section `index` of a window, with `left` bytes of it to go.
+    Section {
+        index: usize,
+        left: u64,
+    },
+}
+
+/// Reads a patch as it is passed to xdelta3, checking it against the limits.
+pub(crate) struct LimitCheck {
+    limits: Limits,
+    stage: Stage,
+    // start of a header, when the header didn't arrive in one piece
+    buf: Vec<u8>,
+    // lengths of the sections of the current window
+    sections: [u64; 3],
+    inst_compressed: bool,
+    target_len: u64,
+    instructions: u64,
+    // size fields still to skip in the instruction section, and whether one is under way
+    sizes: u8,
+    in_varint: bool,
+    // decompressed length of a compressed instruction section, as far as it is read
+    compressed_len: u64,
+    compressed_done: bool,
+}
+
+impl LimitCheck {
+    pub(crate) fn new(limits: Limits) -> Self {
+        Self {
+            limits,
+            stage: Stage::FileHeader,
+            buf: Vec::new(),
+            sections: [0; 3],
+            inst_compressed: false,
+            target_len: 0,
+            instructions: 0,
+            sizes: 0,
+            in_varint: false,
+            compressed_len: 0,
+            compressed_done: false,
+        }
+    }
+
+    /// Takes the next bytes of the patch.
+    pub(crate) fn feed(&mut self, mut data: &[u8]) -> Result<(), Error> {
+        loop {
+            match self.stage {
+                Stage::FileHeader | Stage::WindowHeader => {
+                    if data.is_empty() {
+                        return Ok(());
+                    }
+                    let prev = self.buf.len();
+                    let len = if prev == 0 {
+                        self.parse_header(data)?
+                    } else {
+                        let mut buf = std::mem::take(&mut self.buf);
+                        buf.extend_from_slice(data);
+                        let len = self.parse_header(&buf)?;
+                        self.buf = buf;
+                        len
+                    };
+                    match len {
+                        Some(len) => {
+                            data = &data[len - prev..];
+                            self.buf.clear();
+                        }
+                        None => {
+                            if prev == 0 {
+                                self.buf.extend_from_slice(data);
+                            }
+                            return Ok(());
+                        }
+                    }
+                }
+                Stage::Section { index, left: 0 } => {
+                    self.stage = match index {
+                        2 => Stage::WindowHeader,
+                        _ => Stage::Section {
+                            index: index + 1,
+                            left: self.sections[index + 1],
+                        },
+                    };
+                }
+                Stage::Section { index, left } => {
+                    if data.is_empty() {
+                        return Ok(());
+                    }
+                    let n = (left.min(data.len() as u64)) as usize;
+                    if index == 1 {
+                        self.count(&data[..n])?;
+                    }
+                    data = &data[n..];
+                    self.stage = Stage::Section {
+                        index,
+                        left: left - n as u64,
+                    };
+                }
+            }
+        }
+    }
+
+    /// Parses the header at the start of `data`, returning its length once it is complete.
+    fn parse_header(&mut self, data: &[u8]) -> Result<Option<usize>, Error> {
+        if let Stage::FileHeader = self.stage {
+            let header = vcdiff::parse_header_prefix(data)?;
+            if header.is_some() {
+                self.stage = Stage::WindowHeader;
+            }
+            return Ok(header.map(|h| h.len));
+        }
+
+        let header = match vcdiff::parse_delta_header(data, self.target_len)? {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+        let target_len = header.window.target_len;
+        if header.window.len as u64 > self.limits.window_len {
+            return Err(Error::LimitExceeded("window length"));
+        }
+        if target_len > self.limits.window_target_len {
+            return Err(Error::LimitExceeded("window target length"));
+        }
+        self.target_len = self
+            .target_len
+            .checked_add(target_len)
+            .filter(|&n| n <= self.limits.output_len)
+            .ok_or(Error::LimitExceeded("output length"))?;
+
+        let [data_len, inst_len, addr_len] = header.sections;
+        self.sections = [data_len as u64, inst_len as u64, addr_len as u64];
+        self.inst_compressed = header.delta_indicator & VCD_INSTCOMP != 0;
+        self.sizes = 0;
+        self.in_varint = false;
+        self.compressed_len = 0;
+        self.compressed_done = false;
+        self.stage = Stage::Section {
+            index: 0,
+            left: self.sections[0],
+        };
+        Ok(Some(header.len))
+    }
+
+    /// Counts the instructions in the next bytes of the instruction section.
+    fn count(&mut self, inst: &[u8]) -> Result<(), Error> {
+        for &b in inst {
+            if self.inst_compressed {
+                // the section starts with its decompressed length, the rest is skipped
+                if self.compressed_done {
+                    break;
+                }
+                // a length too large for 64 bits is over any limit
+                self.compressed_len = match self.compressed_len {
+                    n if n >> 57 != 0 => u64::MAX,
+                    n => (n << 7) | (b & 0x7f) as u64,
+                };
+                if b & 0x80 == 0 {
+                    self.compressed_done = true;
+                    self.add_instructions(self.compressed_len.saturating_mul(2))?;
+                }
+            } else if self.in_varint || self.sizes > 0 {
+                self.in_varint = b & 0x80 != 0;
+                if !self.in_varint {
+                    self.sizes -= 1;
+                }
+            } else {
+                let (count, sizes) = vcdiff::code_counts(b);
+                self.sizes = sizes;
+                self.add_instructions(count as u64)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn add_instructions(&mut self, n: u64) -> Result<(), Error> {
+        self.instructions = self
+            .instructions
+            .checked_add(n)
+            .filter(|&n| n <= self.limits.instructions)
+            .ok_or(Error::LimitExceeded("instruction count"))?;
+        Ok(())
+    }
+}
+
+/// Applies `input` to `src` as [`decode`](crate::decode) does, failing with
+/// [`Error::LimitExceeded`] if the patch goes past one of `limits`.
+///
+/// The whole patch is checked before decoding starts, so nothing is decoded from a patch
+/// going past the limits.
+pub fn decode_with_limits(input: &[u8], src: &[u8], limits: &Limits) -> Result<Vec<u8>, Error> {
+    let mut check = LimitCheck::new(*limits);
+    check.feed(input)?;
+    let mut stream = SliceStream::new(src.len())
+        .ok_or_else(|| Error::Xdelta3("failed to set up the decoder".to_owned()))?;
+    let mut out = Vec::new();
+    stream
+        .run(Mode::Decode, input, src, &mut out)
+        .map_err(Error::Xdelta3)?;
+    Ok(out)
+}
@@ -0,0 +1,213 @@
+//! Windowed encoding and decoding of slices, for when `xd3_encode_memory` and
+//! `xd3_decode_memory` can't be used.
+//!
+//! Those take 32-bit lengths and need the whole output to fit in a buffer allocated up front.
+//! Here the input is passed to xdelta3 one window at a time, source blocks point straight
+//! into the source slice, and the output grows as needed.
+
+use super::binding;
+use super::vcdiff::VCD_ADLER32;
+use libc::c_int;
+use log::debug;
+use std::convert::TryFrom;
+
+const WINSIZE: usize = 1 << 23;
+const BLKSIZE: usize = 1 << 20;
+/// Smallest source window. It is larger when the source is, so that copies can come from
+/// anywhere in it.
+const SRCWINSZ: u64 = 1 << 26;
+/// Largest source window xdelta3 takes, `XD3_MAXSRCWINSZ` with 32-bit `usize_t`.
+const MAX_SRCWINSZ: u64 = 1 << 31;
+
+#[derive(Clone, Copy)]
+pub(crate) enum Mode {
+    Encode,
+    Decode,
+}
+
+/// Largest length xdelta3 can take in one call.
+pub(crate) const MAX_LEN: u64 = u32::MAX as u64;
+
+/// An `xd3_stream` reading its source from a slice.
+pub(crate) struct SliceStream {
+    // boxed, as xdelta3 keeps pointers to both
+    stream: Box<binding::xd3_stream>,
+    source: Box<binding::xd3_source>,
+}
+
+impl SliceStream {
+    /// Creates a stream for sources of up to `src_len` bytes.
+    pub(crate) fn new(src_len: usize) -> Option<Self> {
+        let mut this = Self {
+            stream: Box::new(unsafe { std::mem::zeroed() }),
+            source: Box::new(unsafe { std::mem::zeroed() }),
+        };
+        this.source.blksize = BLKSIZE as u32;
+        this.source.max_winsize = (src_len as u64)
+            .next_power_of_two()
+            .clamp(SRCWINSZ, MAX_SRCWINSZ);
+
+        let mut cfg: binding::xd3_config = unsafe { std::mem::zeroed() };
+        cfg.winsize = WINSIZE as u32;
+        if unsafe { binding::xd3_config_stream(&mut *this.stream, &mut cfg) } != 0 {
+            return None;
+        }
+        if unsafe { binding::xd3_set_source(&mut *this.stream, &mut *this.source) } != 0 {
+            return None;
+        }
+        Some(this)
+    }
+
+    /// Passes `input` through the stream, ending with a flush, and the output to `sink`.
+    ///
+    /// The stream can be given more input afterwards, which starts a new window. Returns
+    /// the message of xdelta3 on failure.
+    pub(crate) fn run<S: Sink>(
+        &mut self,
+        mode: Mode,
+        input: &[u8],
+        src: &[u8],
+        sink: &mut S,
+    ) -> Result<(), String> {
+        debug!("run: input_len={}, src_len={}", input.len(), src.len());
+        let stream = &mut *self.stream;
+        let source = &mut *self.source;
+        stream.flags &= !(binding::xd3_flags::XD3_FLUSH as i32);
+
+        let last_blkno = src.len().saturating_sub(1) / BLKSIZE;
+        let mut chunks = input.chunks(WINSIZE);
+        let mut eof = false;
+
+        loop {
+            let ret = unsafe {
+                match mode {
+                    Mode::Encode => binding::xd3_encode_input(stream),
+                    Mode::Decode => binding::xd3_decode_input(stream),
+                }
+            };
+
+            use binding::xd3_rvalues::*;
+            match rvalue(ret).ok_or_else(|| unknown(stream, ret))? {
+                XD3_INPUT => {
+                    if eof {
+                        return Ok(());
+                    }
+                    let chunk = chunks.next().unwrap_or_else(|| {
+                        // xd3_set_flags
+                        stream.flags |= binding::xd3_flags::XD3_FLUSH as i32;
+                        eof = true;
+                        &[]
+                    });
+
+                    // xd3_avail_input
+                    stream.next_in = chunk.as_ptr();
+                    stream.avail_in = chunk.len() as u32;
+                }
+                XD3_OUTPUT => {
+                    let out_data = unsafe {
+                        std::slice::from_raw_parts(stream.next_out, stream.avail_out as usize)
+                    };
+                    sink.output(out_data);
+
+                    // xd3_consume_output
+                    stream.avail_out = 0;
+                }
+                XD3_GETSRCBLK => {
+                    let start = usize::try_from(source.getblkno)
+                        .unwrap_or(usize::MAX)
+                        .saturating_mul(BLKSIZE)
+                        .min(src.len());
+                    let data = &src[start..(start + BLKSIZE).min(src.len())];
+
+                    source.curblkno = source.getblkno;
+                    source.curblk = data.as_ptr();
+                    source.onblk = data.len() as u32;
+                    source.eof_known = 1;
+                    source.max_blkno = last_blkno as u64;
+                    source.onlastblk = (src.len() - last_blkno * BLKSIZE) as u32;
+                }
+                XD3_WINFINISH => sink.window(stream),
+                XD3_GOTHEADER | XD3_WINSTART => {
+                    // do nothing
+                }
+                XD3_TOOFARBACK | XD3_INTERNAL | XD3_INVALID | XD3_INVALID_INPUT | XD3_NOSECOND
+                | XD3_UNIMPLEMENTED => {
+                    return Err(message(stream, "xdelta3 failed"));
+                }
+            }
+        }
+    }
+}
+
+impl Drop for SliceStream {
+    fn drop(&mut self) {
+        unsafe {
+            binding::xd3_free_stream(&mut *self.stream);
+        }
+    }
+}
+
+/// Where the output of a [`SliceStream`] goes.
+pub(crate) trait Sink {
+    fn output(&mut self, data: &[u8]);
+
+    /// Called when a window is finished.
+    fn window(&mut self, _stream: &binding::xd3_stream) {}
+}
+
+impl Sink for Vec<u8> {
+    fn output(&mut self, data: &[u8]) {
+        self.extend_from_slice(data);
+    }
+}
+
+/// Converts what `xd3_encode_input` and `xd3_decode_input` return, which may also be an
+/// `errno` value, or anything if xdelta3 has a bug.
+pub(crate) fn rvalue(ret: c_int) -> Option<binding::xd3_rvalues> {
+    use binding::xd3_rvalues::*;
+    [
+        XD3_INPUT,
+        XD3_OUTPUT,
+        XD3_GETSRCBLK,
+        XD3_GOTHEADER,
+        XD3_WINSTART,
+        XD3_WINFINISH,
+        XD3_TOOFARBACK,
+        XD3_INTERNAL,
+        XD3_INVALID,
+        XD3_INVALID_INPUT,
+        XD3_NOSECOND,
+        XD3_UNIMPLEMENTED,
+    ]
+    .iter()
+    .copied()
+    .find(|&r| r as c_int == ret)
+}
+
+/// Describes a return code [`rvalue`] doesn't know.
+pub(crate) fn unknown(stream: &binding::xd3_stream, ret: c_int) -> String {
+    message(stream, &format!("xdelta3 returned {}", ret))
+}
+
+/// The message xdelta3 left on `stream`, or `default` if there is none.
+pub(crate) fn message(stream: &binding::xd3_stream, default: &str) -> String {
+    if stream.msg.is_null() {
+        return default.to_owned();
+    }
+    unsafe { std::ffi::CStr::from_ptr(stream.msg) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+pub(crate) fn process(mode: Mode, input: &[u8], src: &[u8]) -> Option<Vec<u8>> {
+    let mut output = Vec::new();
+    SliceStream::new(src.len())?
+        .run(mode, input, src, &mut output)
+        .ok()?;
+    Some(output)
+}
+
+/// Whether the window just decoded carried an Adler-32, which xdelta3 has then checked.
+pub(crate) fn checksummed(stream: &binding::xd3_stream) -> bool {
+    stream.dec_win_ind & u32::from(VCD_ADLER32) != 0
+}
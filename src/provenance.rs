@@ -0,0 +1,126 @@
+//! Where each byte of the target of a patch comes from.
+//!
+//! Walking the instructions of a patch tells apart the parts of the target that are new
+//! (literal data and runs) from those found in the source or earlier in the target, for
+//! instance to point out what an update really changes.
+
+use std::ops::Range;
+
+use super::error::Error;
+use super::vcdiff::{Instruction, Patch, SegmentKind, Window};
+
+/// Origin of a range of the target.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Origin {
+    /// Copied from the source, starting at the given offset.
+    Source(u64),
+    /// Copied from the target itself, starting at the given offset.
+    Target(u64),
+    /// Literal data stored in the patch.
+    Literal,
+    /// A run of the given byte.
+    Run(u8),
+}
+
+/// A range of the target and where it comes from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Region {
+    pub target: Range<u64>,
+    pub origin: Origin,
+}
+
+impl Region {
+    /// Whether the bytes of the region are new, rather than found in the source or target.
+    pub fn is_new(&self) -> bool {
+        matches!(self.origin, Origin::Literal | Origin::Run(_))
+    }
+
+    /// Extends the region with `next` if it carries on where this one stops.
+    fn merge(&mut self, next: &Region) -> bool {
+        if self.target.end != next.target.start {
+            return false;
+        }
+        let len = self.target.end - self.target.start;
+        let follows = match (self.origin, next.origin) {
+            (Origin::Source(a), Origin::Source(b)) | (Origin::Target(a), Origin::Target(b)) => {
+                a + len == b
+            }
+            (Origin::Literal, Origin::Literal) => true,
+            (Origin::Run(a), Origin::Run(b)) => a == b,
+            _ => false,
+        };
+        if follows {
+            self.target.end = next.target.end;
+        }
+        follows
+    }
+}
+
+/// Maps the whole target of `patch` to where it comes from.
+///
+/// The regions are in target order, cover the target without gaps, and neighbouring regions
+/// of the same origin are merged. Fails on windows whose instructions are compressed.
+///
+/// ```
+/// extern crate xdelta3;
+/// use xdelta3::provenance::{provenance, Origin, Region};
+///
+/// fn main() {
+///     let patch = [214, 195, 196, 0, 0, 0, 13, 7, 0, 7, 1, 0, 1, 2, 3, 4, 5, 6, 7, 8];
+///     let regions = provenance(&patch).unwrap();
+///     assert_eq!(regions, vec![Region { target: 0..7, origin: Origin::Literal }]);
+/// }
+/// ```
+pub fn provenance(patch: &[u8]) -> Result<Vec<Region>, Error> {
+    let patch = Patch::parse(patch)?;
+    let mut regions: Vec<Region> = Vec::new();
+    for window in patch.windows() {
+        let window = window?;
+        let mut here = window.target_offset;
+        for inst in window.instructions()? {
+            let inst = inst?;
+            let len = inst.len();
+            for (target, origin) in origins(&window, here, &inst) {
+                let region = Region { target, origin };
+                let merged = regions.last_mut().map(|last| last.merge(&region));
+                if merged != Some(true) {
+                    regions.push(region);
+                }
+            }
+            here += len;
+        }
+    }
+    Ok(regions)
+}
+
+/// Origins of the target bytes produced by `inst`, which starts at `here` in the target.
+///
+/// A copy may start in the segment of the window and run on into the target of the window
+/// itself, which makes it two regions.
+fn origins(window: &Window, here: u64, inst: &Instruction) -> Vec<(Range<u64>, Origin)> {
+    let end = here + inst.len();
+    let (addr, len) = match *inst {
+        Instruction::Add(_) => return vec![(here..end, Origin::Literal)],
+        Instruction::Run { byte, .. } => return vec![(here..end, Origin::Run(byte))],
+        Instruction::Copy { addr, len } => (addr, len),
+    };
+
+    let segment_len = window.segment_len();
+    let mut out = Vec::with_capacity(2);
+    let mut target = here;
+    if let Some(segment) = window.segment.filter(|_| addr < segment_len) {
+        let n = len.min(segment_len - addr);
+        let from = segment.position + addr;
+        let origin = match segment.kind {
+            SegmentKind::Source => Origin::Source(from),
+            SegmentKind::Target => Origin::Target(from),
+        };
+        out.push((target..target + n, origin));
+        target += n;
+    }
+    if target < end {
+        let from = window.target_offset + (addr + (target - here) - segment_len);
+        out.push((target..end, Origin::Target(from)));
+    }
+    out
+}
@@ -0,0 +1,220 @@
+//! Building the reverse of a patch out of the forward one.
+//!
+//! Every byte a forward patch copies from the source is a byte of the source found in the
+//! target, so the reverse patch copies it back from there. Only the source bytes nothing
+//! copied are left to be searched for in the target.
+
+use std::convert::TryFrom;
+
+use super::builder::PatchBuilder;
+use super::error::Error;
+use super::vcdiff::{Instruction, Patch, SegmentKind};
+
+/// Most target bytes in a window of the reverse patch.
+const WINDOW: u64 = 1 << 23;
+
+/// Instruction of a patch, located by the offset of what it produces.
+#[derive(Clone, Copy)]
+enum Piece {
+    /// Bytes found in the target of the patch as it is being built.
+    Literal,
+    Run(u8),
+    /// Bytes copied from offset in the source of the patch.
+    Source(u64),
+    /// Bytes copied from offset in the target of the patch.
+    Target(u64),
+}
+
+/// Returns the patch turning `input` back into `src`, given `forward` turning `src` into
+/// `input`. `encode` does the match search for the bytes of `src` that `forward` doesn't
+/// copy, taking the bytes to encode and their source.
+pub(crate) fn encode_reverse<F>(
+    forward: &[u8],
+    input: &[u8],
+    src: &[u8],
+    encode: F,
+) -> Result<Vec<u8>, Error>
+where
+    F: FnOnce(&[u8], &[u8]) -> Result<Vec<u8>, Error>,
+{
+    // (offset in `src`, offset in `input`, length) of every source copy
+    let mut copies = Vec::new();
+    for (start, piece, len) in pieces(forward)? {
+        if let Piece::Source(offset) = piece {
+            if offset + len > src.len() as u64 || start + len > input.len() as u64 {
+                return Err(Error::InvalidPatch("copy past the end of the source"));
+            }
+            copies.push((offset, start, len));
+        }
+    }
+    copies.sort_unstable();
+
+    // `src` split in what is copied back from `input` and the gaps in between, which are
+    // gathered in `rest` for the match search
+    let mut parts = Vec::new();
+    let mut rest = Vec::new();
+    // (offset in `src`, offset in `rest`) of every gap
+    let mut gaps = Vec::new();
+    let mut pos = 0u64;
+    let mut gap = |parts: &mut Vec<_>, start: u64, end: u64| {
+        if start < end {
+            gaps.push((start, rest.len() as u64));
+            rest.extend_from_slice(&src[start as usize..end as usize]);
+            parts.push((None, end - start));
+        }
+    };
+    for (offset, target, len) in copies {
+        let end = offset + len;
+        if end <= pos {
+            continue;
+        }
+        gap(&mut parts, pos, offset);
+        let start = offset.max(pos);
+        parts.push((Some(target + (start - offset)), end - start));
+        pos = end;
+    }
+    gap(&mut parts, pos, src.len() as u64);
+
+    let mut rest_pieces = if rest.is_empty() {
+        Vec::new()
+    } else {
+        pieces(&encode(&rest, input)?)?
+    }
+    .into_iter()
+    .map(|(_, piece, len)| (piece, len));
+
+    let mut out = Writer {
+        src,
+        builder: PatchBuilder::new(),
+        written: 0,
+        window_start: 0,
+    };
+    // the piece of the patch of `rest` being written, and how much of it is
+    let mut current = None;
+    for (target, len) in parts {
+        if let Some(target) = target {
+            out.push(Piece::Source(target), len);
+            continue;
+        }
+        let mut left = len;
+        while left > 0 {
+            let (piece, piece_len, done) = match current.take() {
+                Some(c) => c,
+                None => {
+                    let (piece, piece_len) = rest_pieces
+                        .next()
+                        .ok_or(Error::InvalidPatch("patch shorter than its target"))?;
+                    (piece, piece_len, 0)
+                }
+            };
+            let n = left.min(piece_len - done);
+            let located = match piece {
+                Piece::Source(offset) => Piece::Source(offset + done),
+                // where the copied bytes of `rest` are in `src`
+                Piece::Target(offset) => {
+                    let offset = offset + done;
+                    let i = gaps.partition_point(|&(_, r)| r <= offset) - 1;
+                    Piece::Target(gaps[i].0 + (offset - gaps[i].1))
+                }
+                piece => piece,
+            };
+            out.push(located, n);
+            if done + n < piece_len {
+                current = Some((piece, piece_len, done + n));
+            }
+            left -= n;
+        }
+    }
+    out.builder.finish()
+}
+
+/// Returns the instructions of `patch` as (target offset, piece, length), with copies from
+/// the target located in the target as a whole.
+fn pieces(patch: &[u8]) -> Result<Vec<(u64, Piece, u64)>, Error> {
+    let mut pieces = Vec::new();
+    for window in Patch::parse(patch)?.windows() {
+        let window = window?;
+        let (position, segment_len) = match window.segment {
+            Some(s) if s.kind == SegmentKind::Source => (s.position, s.len),
+            Some(_) => return Err(Error::Unsupported("copies from an earlier window")),
+            None => (0, 0),
+        };
+        let mut here = window.target_offset;
+        for inst in window.instructions()? {
+            let inst = inst?;
+            let len = inst.len();
+            match inst {
+                Instruction::Add(_) => pieces.push((here, Piece::Literal, len)),
+                Instruction::Run { byte, .. } => pieces.push((here, Piece::Run(byte), len)),
+                Instruction::Copy { addr, .. } => {
+                    // a copy may run from the end of the segment into the target
+                    let n = len.min(segment_len.saturating_sub(addr));
+                    if n > 0 {
+                        pieces.push((here, Piece::Source(position + addr), n));
+                    }
+                    if n < len {
+                        let addr = (addr + n) - segment_len;
+                        let piece = Piece::Target(window.target_offset + addr);
+                        pieces.push((here + n, piece, len - n));
+                    }
+                }
+            }
+            here += len;
+        }
+    }
+    Ok(pieces)
+}
+
+/// Writes the reverse patch, whose target is `src`.
+struct Writer<'a> {
+    src: &'a [u8],
+    builder: PatchBuilder,
+    written: u64,
+    window_start: u64,
+}
+
+impl<'a> Writer<'a> {
+    fn push(&mut self, piece: Piece, len: u64) {
+        let mut done = 0;
+        while done < len {
+            if self.written - self.window_start == WINDOW {
+                self.builder.end_window();
+                self.window_start = self.written;
+            }
+            let n = (len - done).min(WINDOW - (self.written - self.window_start));
+            let here = self.bytes(self.written, n);
+            match piece {
+                Piece::Literal => {
+                    self.builder.add(here);
+                }
+                Piece::Run(byte) => {
+                    self.builder.run(byte, n);
+                }
+                Piece::Source(offset) => {
+                    self.builder.copy_from_source(offset + done, n);
+                }
+                // the copy is checked against `src`, since the bytes of `rest` it comes
+                // from may be apart in there, or in an earlier window
+                Piece::Target(offset) => {
+                    let offset = offset + done;
+                    if offset >= self.window_start
+                        && offset < self.written
+                        && self.bytes(offset, n) == here
+                    {
+                        self.builder.copy_from_target(offset, n);
+                    } else {
+                        self.builder.add(here);
+                    }
+                }
+            }
+            self.written += n;
+            done += n;
+        }
+    }
+
+    fn bytes(&self, offset: u64, len: u64) -> &'a [u8] {
+        let start = usize::try_from(offset).unwrap_or(usize::MAX);
+        let end = usize::try_from(offset + len).unwrap_or(usize::MAX);
+        &self.src[start..end]
+    }
+}
@@ -0,0 +1,125 @@
+//! Signed patches, checked before they are applied.
+//!
+//! A signed patch is the VCDIFF stream followed by a trailer holding the SHA-256 of the
+//! source and of the target, and an Ed25519 signature over the hash of the patch and
+//! those two hashes. [`decode_verified`] refuses to produce anything unless the
+//! signature checks out against a trusted key and the source is the one the patch was
+//! made for.
+
+use ed25519_dalek::{Signature, Signer};
+use sha2::{Digest, Sha256};
+
+use super::error::Error;
+use super::try_decode;
+
+pub use ed25519_dalek::{SigningKey, VerifyingKey};
+
+/// Marks the end of a signed patch.
+const TRAILER_MAGIC: [u8; 8] = *b"XD3SIGv1";
+const HASH_LEN: usize = 32;
+const TRAILER_LEN: usize = 2 * HASH_LEN + Signature::BYTE_SIZE + TRAILER_MAGIC.len();
+// keeps signatures from being taken for those of anything else
+const DOMAIN: &[u8] = b"xdelta3-rs signed patch";
+
+struct Trailer {
+    source_hash: [u8; HASH_LEN],
+    target_hash: [u8; HASH_LEN],
+    signature: Signature,
+}
+
+fn sha256(data: &[u8]) -> [u8; HASH_LEN] {
+    Sha256::digest(data).into()
+}
+
+fn message(patch: &[u8], source_hash: &[u8], target_hash: &[u8]) -> Vec<u8> {
+    let mut message = DOMAIN.to_vec();
+    message.extend_from_slice(&sha256(patch));
+    message.extend_from_slice(source_hash);
+    message.extend_from_slice(target_hash);
+    message
+}
+
+/// Signs `patch`, which turns `source` into `target`, with `key`.
+///
+/// ```
+/// extern crate xdelta3;
+/// use xdelta3::encode;
+/// use xdelta3::signing::{decode_verified, sign, SigningKey};
+///
+/// fn main() {
+///     let key = SigningKey::from_bytes(&[7; 32]);
+///     let (source, target) = (&[1, 2, 4, 4, 7, 6, 7], &[1, 2, 3, 4, 5, 6, 7]);
+///     let patch = encode(target, source).unwrap();
+///     let signed = sign(&patch, source, target, &key);
+///
+///     let out = decode_verified(&signed, source, &key.verifying_key()).unwrap();
+///     assert_eq!(out, target);
+/// }
+/// ```
+pub fn sign(patch: &[u8], source: &[u8], target: &[u8], key: &SigningKey) -> Vec<u8> {
+    let source_hash = sha256(source);
+    let target_hash = sha256(target);
+    let signature = key.sign(&message(patch, &source_hash, &target_hash));
+
+    let mut signed = Vec::with_capacity(patch.len() + TRAILER_LEN);
+    signed.extend_from_slice(patch);
+    signed.extend_from_slice(&source_hash);
+    signed.extend_from_slice(&target_hash);
+    signed.extend_from_slice(&signature.to_bytes());
+    signed.extend_from_slice(&TRAILER_MAGIC);
+    signed
+}
+
+/// Splits a signed patch into the patch and its trailer.
+fn split(signed: &[u8]) -> Result<(&[u8], Trailer), Error> {
+    let patch_len = signed
+        .len()
+        .checked_sub(TRAILER_LEN)
+        .filter(|_| signed.ends_with(&TRAILER_MAGIC))
+        .ok_or(Error::BadSignature("patch is not signed"))?;
+    let (patch, trailer) = signed.split_at(patch_len);
+    let mut source_hash = [0; HASH_LEN];
+    let mut target_hash = [0; HASH_LEN];
+    let mut signature = [0; Signature::BYTE_SIZE];
+    source_hash.copy_from_slice(&trailer[..HASH_LEN]);
+    target_hash.copy_from_slice(&trailer[HASH_LEN..2 * HASH_LEN]);
+    signature.copy_from_slice(&trailer[2 * HASH_LEN..2 * HASH_LEN + Signature::BYTE_SIZE]);
+    let trailer = Trailer {
+        source_hash,
+        target_hash,
+        signature: Signature::from_bytes(&signature),
+    };
+    Ok((patch, trailer))
+}
+
+/// Checks the signature of a signed patch against `key`, and returns the patch inside.
+///
+/// This doesn't check the source; use [`decode_verified`] to apply the patch.
+pub fn verify_signature<'a>(signed: &'a [u8], key: &VerifyingKey) -> Result<&'a [u8], Error> {
+    check(signed, key).map(|(patch, _)| patch)
+}
+
+fn check<'a>(signed: &'a [u8], key: &VerifyingKey) -> Result<(&'a [u8], Trailer), Error> {
+    let (patch, trailer) = split(signed)?;
+    let message = message(patch, &trailer.source_hash, &trailer.target_hash);
+    key.verify_strict(&message, &trailer.signature)
+        .map_err(|_| Error::BadSignature("signature doesn't match"))?;
+    Ok((patch, trailer))
+}
+
+/// Applies a signed patch to `source`, as [`decode`](crate::decode) does, once its
+/// signature is checked against `key`.
+///
+/// Nothing is decoded unless the signature is valid and `source` is the source the patch
+/// was signed for, and the decoded target is only returned if it has the signed hash.
+pub fn decode_verified(signed: &[u8], source: &[u8], key: &VerifyingKey) -> Result<Vec<u8>, Error> {
+    let (patch, trailer) = check(signed, key)?;
+    if sha256(source) != trailer.source_hash {
+        return Err(Error::BadSignature("patch is signed for another source"));
+    }
+    let target = try_decode(patch, source)?;
+    if sha256(&target) != trailer.target_hash {
+        return Err(Error::BadSignature("target doesn't match the signed hash"));
+    }
+    Ok(target)
+}
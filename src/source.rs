@@ -0,0 +1,115 @@
+//! Providers of source data.
+//!
+//! The encoder and decoder ask for the source one block at a time. Besides readers, which
+//! the stream functions accept directly, anything able to hand out blocks by number can be
+//! used as the source by implementing [`Source`] or [`AsyncSource`].
+//!
+//! ```
+//! use std::io;
+//! use xdelta3::source::Source;
+//!
+//! /// A source split in chunks of any size, as kept by a content-addressed store.
+//! struct Chunks {
+//!     chunks: Vec<Vec<u8>>,
+//!     block: Vec<u8>,
+//! }
+//!
+//! impl Source for Chunks {
+//!     fn get_block(&mut self, blkno: u64, blksize: usize) -> io::Result<&[u8]> {
+//!         let (start, end) = (blkno as usize * blksize, (blkno as usize + 1) * blksize);
+//!         self.block.clear();
+//!         let mut pos = 0;
+//!         for chunk in &self.chunks {
+//!             let (s, e) = (start.max(pos), end.min(pos + chunk.len()));
+//!             if s < e {
+//!                 self.block.extend_from_slice(&chunk[s - pos..e - pos]);
+//!             }
+//!             pos += chunk.len();
+//!         }
+//!         Ok(&self.block)
+//!     }
+//!
+//!     fn size(&self) -> Option<u64> {
+//!         Some(self.chunks.iter().map(|c| c.len() as u64).sum())
+//!     }
+//! }
+//! ```
+
+use futures_io::Result;
+use std::convert::TryFrom;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// A source that can be read block by block.
+pub trait Source {
+    /// Returns block `blkno`, which is the data from `blkno * blksize` on.
+    ///
+    /// Every block is `blksize` bytes long, except the last one which may be shorter.
+    /// Blocks past the end of the source are empty.
+    fn get_block(&mut self, blkno: u64, blksize: usize) -> Result<&[u8]>;
+
+    /// Length of the source, if it is known.
+    ///
+    /// When it isn't, the end of the source is found at the first block shorter than
+    /// `blksize`.
+    fn size(&self) -> Option<u64> {
+        None
+    }
+}
+
+/// A source that can be read block by block without blocking.
+///
+/// Every [`Source`] is an `AsyncSource` too, which blocks when fetching a block.
+pub trait AsyncSource {
+    /// Attempts to return block `blkno`, as [`Source::get_block`] does.
+    ///
+    /// If the block isn't available yet, returns `Poll::Pending` and arranges for the current
+    /// task to be woken up once it is.
+    fn poll_block<'a>(
+        self: Pin<&'a mut Self>,
+        cx: &mut Context,
+        blkno: u64,
+        blksize: usize,
+    ) -> Poll<Result<&'a [u8]>>;
+
+    /// Makes progress on loading the `blocks` blocks past the last one asked for, so they
+    /// are ready when they are needed.
+    ///
+    /// Called while the encoder or decoder is busy. Errors should be kept for when the block
+    /// is asked for. Does nothing by default.
+    fn poll_read_ahead(self: Pin<&mut Self>, _cx: &mut Context, _blksize: usize, _blocks: usize) {}
+
+    /// Length of the source, if it is known. See [`Source::size`].
+    fn size(&self) -> Option<u64> {
+        None
+    }
+}
+
+impl<S: Source + Unpin + ?Sized> AsyncSource for S {
+    fn poll_block<'a>(
+        self: Pin<&'a mut Self>,
+        _cx: &mut Context,
+        blkno: u64,
+        blksize: usize,
+    ) -> Poll<Result<&'a [u8]>> {
+        Poll::Ready(self.get_mut().get_block(blkno, blksize))
+    }
+
+    fn size(&self) -> Option<u64> {
+        Source::size(self)
+    }
+}
+
+impl Source for &[u8] {
+    fn get_block(&mut self, blkno: u64, blksize: usize) -> Result<&[u8]> {
+        let start = usize::try_from(blkno)
+            .unwrap_or(usize::MAX)
+            .saturating_mul(blksize)
+            .min(self.len());
+        Ok(&self[start..start.saturating_add(blksize).min(self.len())])
+    }
+
+    fn size(&self) -> Option<u64> {
+        Some(self.len() as u64)
+    }
+}
@@ -0,0 +1,323 @@
+//! Sparse targets, for disk images and other files made mostly of zeros.
+//!
+//! [`SparseFile`] writes decoded data to a file, leaving holes where whole blocks of zeros
+//! go instead of writing them out, whether they come from RUN instructions, literal data
+//! or copies. [`encode_sparse`] keeps long zero regions of the target away from xdelta3
+//! and writes them as RUN windows, which are cheap to make and to apply.
+
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom, Write};
+use std::ops::Range;
+
+use super::builder::PatchBuilder;
+use super::error::Error;
+use super::try_encode;
+use super::vcdiff::{self, Patch};
+
+/// Size of the blocks checked for zeros, aligned on offsets of the file.
+const BLOCK: usize = 4096;
+/// Data held before writing it in one go.
+const MAX_PENDING: usize = 1 << 20;
+/// Shortest zero region [`encode_sparse`] takes out of the target.
+const MIN_ZERO_RUN: usize = 1 << 16;
+/// Target of the RUN windows written by [`encode_sparse`].
+const RUN_WINDOW: u64 = 1 << 23;
+
+/// A file written with holes in place of blocks of zeros
+///
+/// Writes start at the beginning of the file. When the file already has content, blocks of
+/// zeros written over it are turned into holes as well (on Linux, by punching them; on other
+/// systems, or file systems without holes, the zeros are written out). Content past the end
+/// of what is written is left as it is.
+///
+/// Writes are buffered: the file is only complete once the writer is flushed, which the
+/// streaming decoders do when they are done. Dropping the writer flushes it as well, but
+/// ignores errors; [`into_inner`](SparseFile::into_inner) reports them.
+///
+/// ```no_run
+/// extern crate xdelta3;
+/// use std::fs::{self, File};
+/// use std::io::Write;
+/// use xdelta3::decode;
+/// use xdelta3::sparse::SparseFile;
+///
+/// fn main() {
+///     let patch = fs::read("disk.img.vcdiff").unwrap();
+///     let source = fs::read("disk-old.img").unwrap();
+///     let mut target = SparseFile::new(File::create("disk.img").unwrap()).unwrap();
+///     target.write_all(&decode(&patch, &source).unwrap()).unwrap();
+///     target.into_inner().unwrap().sync_all().unwrap();
+/// }
+/// ```
+#[derive(Debug)]
+pub struct SparseFile {
+    // only taken by `into_inner`
+    file: Option<File>,
+    // bytes of the block being filled, which starts at `block_start`
+    block: Vec<u8>,
+    block_start: u64,
+    // data ready to be written at `data_start`
+    data: Vec<u8>,
+    data_start: u64,
+    // zero blocks not turned into a hole yet
+    hole: Range<u64>,
+    // length of the file on disk
+    disk_len: u64,
+    // end of what was written, holes included
+    end: u64,
+}
+
+impl SparseFile {
+    pub fn new(file: File) -> io::Result<Self> {
+        let disk_len = file.metadata()?.len();
+        Ok(Self {
+            file: Some(file),
+            block: Vec::with_capacity(BLOCK),
+            block_start: 0,
+            data: Vec::new(),
+            data_start: 0,
+            hole: 0..0,
+            disk_len,
+            end: 0,
+        })
+    }
+
+    pub fn get_ref(&self) -> &File {
+        self.file.as_ref().expect("file taken")
+    }
+
+    /// Flushes the writer and returns the file.
+    pub fn into_inner(mut self) -> io::Result<File> {
+        self.flush()?;
+        Ok(self.file.take().expect("file taken"))
+    }
+
+    fn file(&mut self) -> &mut File {
+        self.file.as_mut().expect("file taken")
+    }
+
+    /// Hands the block being filled on to be written, or to become a hole.
+    fn end_block(&mut self) -> io::Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+        let start = self.block_start;
+        let end = start + self.block.len() as u64;
+        // blocks stop at multiples of BLOCK, so a full one is aligned
+        if self.block.len() == BLOCK && self.block.iter().all(|&b| b == 0) {
+            self.write_data()?;
+            if self.hole.end != start {
+                self.punch()?;
+                self.hole.start = start;
+            }
+            self.hole.end = end;
+        } else {
+            self.punch()?;
+            if self.data_start + self.data.len() as u64 != start {
+                self.write_data()?;
+                self.data_start = start;
+            }
+            self.data.extend_from_slice(&self.block);
+            if self.data.len() >= MAX_PENDING {
+                self.write_data()?;
+            }
+        }
+        self.block.clear();
+        self.block_start = end;
+        self.end = self.end.max(end);
+        Ok(())
+    }
+
+    fn write_data(&mut self) -> io::Result<()> {
+        if self.data.is_empty() {
+            return Ok(());
+        }
+        let file = self.file.as_mut().expect("file taken");
+        file.seek(SeekFrom::Start(self.data_start))?;
+        file.write_all(&self.data)?;
+        self.data_start += self.data.len() as u64;
+        self.disk_len = self.disk_len.max(self.data_start);
+        self.data.clear();
+        Ok(())
+    }
+
+    /// Makes a hole of the zero blocks seen last. Only those over the content of the file
+    /// need anything done, the others are holes already once the file is extended.
+    fn punch(&mut self) -> io::Result<()> {
+        let hole = self.hole.start..self.hole.end.min(self.disk_len);
+        self.hole = 0..0;
+        if hole.start < hole.end {
+            punch_hole(self.file(), hole)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn punch_hole(file: &mut File, hole: Range<u64>) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let ret = unsafe {
+        libc::fallocate(
+            file.as_raw_fd(),
+            libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+            hole.start as libc::off_t,
+            (hole.end - hole.start) as libc::off_t,
+        )
+    };
+    if ret == 0 {
+        return Ok(());
+    }
+    let err = io::Error::last_os_error();
+    if err.raw_os_error() != Some(libc::EOPNOTSUPP) {
+        return Err(err);
+    }
+    write_zeros(file, hole)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn punch_hole(file: &mut File, hole: Range<u64>) -> io::Result<()> {
+    write_zeros(file, hole)
+}
+
+fn write_zeros(file: &mut File, hole: Range<u64>) -> io::Result<()> {
+    let zeros = [0; BLOCK];
+    file.seek(SeekFrom::Start(hole.start))?;
+    let mut left = hole.end - hole.start;
+    while left > 0 {
+        let n = left.min(BLOCK as u64) as usize;
+        file.write_all(&zeros[..n])?;
+        left -= n as u64;
+    }
+    Ok(())
+}
+
+impl Write for SparseFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let pos = self.block_start + self.block.len() as u64;
+        // room left in the block `pos` is in
+        let room = BLOCK - (pos % BLOCK as u64) as usize;
+        let n = room.min(buf.len());
+        self.block.extend_from_slice(&buf[..n]);
+        if n == room {
+            self.end_block()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.end_block()?;
+        self.write_data()?;
+        self.punch()?;
+        if self.disk_len < self.end {
+            // trailing zeros become a hole as well
+            let end = self.end;
+            self.file().set_len(end)?;
+            self.disk_len = end;
+        }
+        self.file().flush()
+    }
+}
+
+impl Drop for SparseFile {
+    fn drop(&mut self) {
+        if self.file.is_some() {
+            // as `BufWriter` does, errors are only reported by an explicit flush
+            let _ = self.flush();
+        }
+    }
+}
+
+impl Seek for SparseFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.flush()?;
+        let pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(delta) => offset(self.end.max(self.disk_len), delta),
+            SeekFrom::Current(delta) => offset(self.block_start, delta),
+        }
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid seek"))?;
+        self.block_start = pos;
+        Ok(pos)
+    }
+}
+
+fn offset(base: u64, delta: i64) -> Option<u64> {
+    if delta < 0 {
+        base.checked_sub(delta.unsigned_abs())
+    } else {
+        base.checked_add(delta as u64)
+    }
+}
+
+/// Makes a patch from `src` to `input` as [`encode`](crate::encode) does, except that zero
+/// regions of at least 64 KiB are written as RUN windows rather than handed to xdelta3.
+///
+/// Encoding a disk image this way skips searching the source for its empty parts, and
+/// the windows of zeros let [`SparseFile`] leave holes in their place. The result is an
+/// ordinary patch.
+///
+/// ```
+/// extern crate xdelta3;
+/// use xdelta3::decode;
+/// use xdelta3::sparse::encode_sparse;
+///
+/// fn main() {
+///     let mut image = vec![0; 1 << 20];
+///     image[..5].copy_from_slice(b"disk!");
+///     let patch = encode_sparse(&image, b"disk?").unwrap();
+///     assert!(patch.len() < 100);
+///     assert_eq!(decode(&patch, b"disk?").unwrap(), image);
+/// }
+/// ```
+pub fn encode_sparse(input: &[u8], src: &[u8]) -> Result<Vec<u8>, Error> {
+    let zeros = zero_regions(input);
+    if zeros.is_empty() {
+        return try_encode(input, src);
+    }
+
+    let mut out = Vec::new();
+    vcdiff::write_header(&mut out);
+    let mut start = 0;
+    for zero in zeros.into_iter().chain(Some(input.len()..input.len())) {
+        if start < zero.start {
+            let encoded = try_encode(&input[start..zero.start], src)?;
+            let patch = Patch::parse(&encoded)?;
+            if patch.header().secondary.is_some() {
+                return Err(Error::Unsupported("secondary compression"));
+            }
+            for window in patch.windows() {
+                let window = window?;
+                out.extend_from_slice(&encoded[window.offset..][..window.len]);
+            }
+        }
+        let mut builder = PatchBuilder::new();
+        let mut left = (zero.end - zero.start) as u64;
+        while left > 0 {
+            let len = left.min(RUN_WINDOW);
+            builder.run(0, len).end_window();
+            left -= len;
+        }
+        builder.write_windows(&mut out, None)?;
+        start = zero.end;
+    }
+    Ok(out)
+}
+
+/// Zero regions of `input` worth a window of their own, made of whole blocks.
+fn zero_regions(input: &[u8]) -> Vec<Range<usize>> {
+    let mut regions = Vec::new();
+    let mut run: Option<Range<usize>> = None;
+    for (i, block) in input.chunks(BLOCK).enumerate() {
+        let start = i * BLOCK;
+        if block.iter().all(|&b| b == 0) {
+            let end = start + block.len();
+            run = Some(run.map_or(start..end, |r| r.start..end));
+        } else if let Some(r) = run.take() {
+            regions.push(r);
+        }
+    }
+    regions.extend(run);
+    regions.retain(|r| r.end - r.start >= MIN_ZERO_RUN);
+    regions
+}
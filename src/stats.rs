@@ -0,0 +1,220 @@
+//! Figures about what a patch is made of, for tuning how patches are made.
+//!
+//! Everything here is read off the VCDIFF structure of the patch, without decoding it.
+
+use std::convert::TryFrom;
+
+use super::error::Error;
+use super::vcdiff::{Instruction, Patch, SegmentKind, Window};
+
+/// Bytes produced by each kind of instruction, and how many instructions of each kind there
+/// are.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct InstructionStats {
+    /// Literal bytes, from ADD instructions.
+    pub add_bytes: u64,
+    /// Bytes copied from the source.
+    pub source_copy_bytes: u64,
+    /// Bytes copied from the target, whether from earlier windows or the window itself.
+    pub target_copy_bytes: u64,
+    /// Bytes from RUN instructions.
+    pub run_bytes: u64,
+    pub adds: u64,
+    pub copies: u64,
+    pub runs: u64,
+}
+
+impl InstructionStats {
+    fn add(&mut self, other: &InstructionStats) {
+        self.add_bytes += other.add_bytes;
+        self.source_copy_bytes += other.source_copy_bytes;
+        self.target_copy_bytes += other.target_copy_bytes;
+        self.run_bytes += other.run_bytes;
+        self.adds += other.adds;
+        self.copies += other.copies;
+        self.runs += other.runs;
+    }
+}
+
+/// Size of a section of a window, as stored and once decompressed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Section {
+    pub stored_len: u64,
+    pub len: u64,
+}
+
+impl Section {
+    /// Bytes saved by secondary compression; negative if compressing made it larger.
+    pub fn saved(&self) -> i64 {
+        let signed = |n: u64| i64::try_from(n).unwrap_or(i64::MAX);
+        signed(self.len).saturating_sub(signed(self.stored_len))
+    }
+
+    fn add(&mut self, other: &Section) {
+        self.stored_len += other.stored_len;
+        // decompressed lengths are whatever the patch claims
+        self.len = self.len.saturating_add(other.len);
+    }
+}
+
+/// Figures of a single window.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WindowStats {
+    /// Offset of the window in the patch.
+    pub offset: u64,
+    /// Number of bytes taken by the window in the patch.
+    pub len: u64,
+    pub target_offset: u64,
+    pub target_len: u64,
+    pub data: Section,
+    pub inst: Section,
+    pub addr: Section,
+    /// Only known when the instructions aren't compressed, see [`Window::instructions`].
+    pub instructions: Option<InstructionStats>,
+}
+
+/// Figures of a whole patch.
+///
+/// ```
+/// extern crate xdelta3;
+/// use xdelta3::stats::PatchStats;
+///
+/// fn main() {
+///     let patch = [214, 195, 196, 0, 0, 0, 13, 7, 0, 7, 1, 0, 1, 2, 3, 4, 5, 6, 7, 8];
+///     let stats = PatchStats::new(&patch, None).unwrap();
+///     assert_eq!(stats.target_len, 7);
+///     assert_eq!(stats.instructions.add_bytes, 7);
+///     assert_eq!(stats.instructions.adds, 1);
+/// }
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PatchStats {
+    pub patch_len: u64,
+    pub target_len: u64,
+    /// Length of the source, if it was given.
+    pub source_len: Option<u64>,
+    /// Number of distinct source bytes copied from.
+    pub source_used: u64,
+    pub windows: Vec<WindowStats>,
+    /// Totals over the windows whose instructions could be read.
+    pub instructions: InstructionStats,
+    /// Number of windows whose instructions couldn't be read.
+    pub unread_windows: u64,
+    pub data: Section,
+    pub inst: Section,
+    pub addr: Section,
+}
+
+impl PatchStats {
+    /// Gathers the figures of `patch`. With the length of the source, the share of it the
+    /// patch uses can be told.
+    pub fn new(patch: &[u8], source_len: Option<u64>) -> Result<Self, Error> {
+        let patch_len = patch.len() as u64;
+        let patch = Patch::parse(patch)?;
+        let mut stats = Self {
+            patch_len,
+            target_len: 0,
+            source_len,
+            source_used: 0,
+            windows: Vec::new(),
+            instructions: InstructionStats::default(),
+            unread_windows: 0,
+            data: Section::default(),
+            inst: Section::default(),
+            addr: Section::default(),
+        };
+        // ranges of the source copied from
+        let mut used = Vec::new();
+
+        for window in patch.windows() {
+            let window = window?;
+            let [data, inst, addr] = window.section_lens()?;
+            let section = |stored: &[u8], len| Section {
+                stored_len: stored.len() as u64,
+                len,
+            };
+            let instructions = if window.is_compressed() {
+                None
+            } else {
+                Some(instruction_stats(&window, &mut used)?)
+            };
+            let w = WindowStats {
+                offset: window.offset as u64,
+                len: window.len as u64,
+                target_offset: window.target_offset,
+                target_len: window.target_len,
+                data: section(window.data, data),
+                inst: section(window.inst, inst),
+                addr: section(window.addr, addr),
+                instructions,
+            };
+
+            stats.target_len += w.target_len;
+            stats.data.add(&w.data);
+            stats.inst.add(&w.inst);
+            stats.addr.add(&w.addr);
+            match &w.instructions {
+                Some(i) => stats.instructions.add(i),
+                None => stats.unread_windows += 1,
+            }
+            stats.windows.push(w);
+        }
+
+        used.sort_unstable();
+        let mut end = 0;
+        for (start, stop) in used {
+            let start = start.max(end);
+            if stop > start {
+                stats.source_used += stop - start;
+                end = stop;
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Size of the patch relative to the target.
+    pub fn ratio(&self) -> f64 {
+        self.patch_len as f64 / self.target_len.max(1) as f64
+    }
+
+    /// Share of the source the patch copies from, if its length is known.
+    pub fn source_use(&self) -> Option<f64> {
+        self.source_len
+            .map(|len| self.source_used as f64 / len.max(1) as f64)
+    }
+}
+
+/// Counts the instructions of `window`, adding the source ranges it copies to `used`.
+fn instruction_stats(
+    window: &Window,
+    used: &mut Vec<(u64, u64)>,
+) -> Result<InstructionStats, Error> {
+    let mut stats = InstructionStats::default();
+    let segment_len = window.segment_len();
+    for inst in window.instructions()? {
+        match inst? {
+            Instruction::Add(data) => {
+                stats.adds += 1;
+                stats.add_bytes += data.len() as u64;
+            }
+            Instruction::Run { len, .. } => {
+                stats.runs += 1;
+                stats.run_bytes += len;
+            }
+            Instruction::Copy { addr, len } => {
+                stats.copies += 1;
+                match window.segment {
+                    Some(segment) if addr < segment_len && segment.kind == SegmentKind::Source => {
+                        // a copy may run on past the segment into the window's own target
+                        let n = len.min(segment_len - addr);
+                        stats.source_copy_bytes += n;
+                        stats.target_copy_bytes += len - n;
+                        used.push((segment.position + addr, segment.position + addr + n));
+                    }
+                    _ => stats.target_copy_bytes += len,
+                }
+            }
+        }
+    }
+    Ok(stats)
+}
@@ -1,143 +1,240 @@
 use futures_io::*;
 use futures_util::io::*;
+use std::alloc::GlobalAlloc;
+use std::convert::TryFrom;
 use std::ops::Range;
+use std::sync::{Arc, Mutex, PoisonError};
 
-use super::binding;
+use super::alloc::Allocator;
+use super::error::Error;
+use super::index::PatchIndex;
+use super::limits::{LimitCheck, Limits};
+use super::memory::Mode;
+use super::source::{AsyncSource, Source};
+use super::vcdiff;
+use super::verify::Verification;
+use super::worker::{Block, Event, Request, Slice, Worker, XD3_DEFAULT_WINSIZE};
+use bytes::Bytes;
+use futures_channel::mpsc;
+use futures_util::future::poll_fn;
+use futures_util::ready;
+use futures_util::stream::{Stream, TryStreamExt};
 use log::debug;
+use std::pin::Pin;
+use std::task::{Context, Poll};
 
-#[allow(unused)]
-const XD3_DEFAULT_WINSIZE: usize = 1 << 23;
 const XD3_DEFAULT_SRCWINSZ: usize = 1 << 26;
+const BLOCK_COUNT: usize = 64;
+const BLKSIZE: usize = XD3_DEFAULT_SRCWINSZ / BLOCK_COUNT;
+const DEFAULT_READ_AHEAD: usize = 4;
+const MAX_READ_AHEAD: usize = BLOCK_COUNT / 2;
 
+/// Source read from an `AsyncRead`, keeping the last blocks read in a ring buffer.
+///
+/// The ring has a slot more than the blocks kept, for the block being read, so the last
+/// `BLOCK_COUNT` complete blocks stay available while it comes in, and spare slots for the
+/// blocks read ahead of the last one asked for.
+///
+/// Blocks are lent to the worker as they are in the ring. A slot still held by the worker
+/// when its turn to be reused comes is left to it, and replaced by a new one.
 struct SrcBuffer<R> {
-    src: binding::xd3_source,
     read: R,
     read_len: usize,
     eof_known: bool,
+    // error met while reading ahead, reported when the block is asked for
+    error: Option<std::io::Error>,
 
-    block_count: usize,
-    block_offset: usize,
-    buf: Box<[u8]>,
+    // number of blocks read, and bytes read of the next one
+    loaded: usize,
+    partial: usize,
+    // one past the last block asked for
+    wanted: usize,
+    slots: Vec<Arc<Vec<u8>>>,
 }
 
-impl<R: AsyncRead + Unpin> SrcBuffer<R> {
-    async fn new(mut read: R) -> Option<Self> {
-        let block_count = 64;
-        let max_winsize = XD3_DEFAULT_SRCWINSZ;
-        let blksize = max_winsize / block_count;
-
-        let mut src: binding::xd3_source = unsafe { std::mem::zeroed() };
-        src.blksize = blksize as u32;
-        src.max_winsize = max_winsize as u64;
-
-        let mut buf = Vec::with_capacity(max_winsize);
-        buf.resize(max_winsize, 0u8);
-
-        let read_len = read.read(&mut buf).await.ok()?;
-        debug!("SrcBuffer::new read_len={}", read_len);
-
-        Some(Self {
-            src,
+impl<R> SrcBuffer<R> {
+    fn new(read: R, read_ahead: usize) -> Self {
+        let slots = BLOCK_COUNT + 1 + read_ahead.min(MAX_READ_AHEAD);
+        let slots = (0..slots).map(|_| Arc::new(vec![0u8; BLKSIZE])).collect();
+        Self {
             read,
-            read_len,
-            eof_known: read_len != buf.len(),
-
-            block_count,
-            block_offset: 0,
-            buf: buf.into_boxed_slice(),
-        })
-    }
+            read_len: 0,
+            eof_known: false,
+            error: None,
 
-    async fn fetch(&mut self) -> Option<bool> {
-        let idx = self.block_offset;
-        let r = self.block_range(idx);
-        let block = &mut self.buf[r.clone()];
-        let read_len = self.read.read(block).await.ok()?;
-        debug!(
-            "range={:?}, block_len={}, read_len={}",
-            r,
-            block.len(),
-            read_len
-        );
-
-        self.block_offset += 1;
-        self.read_len += read_len;
-
-        Some(read_len != block.len())
+            loaded: 0,
+            partial: 0,
+            wanted: 0,
+            slots,
+        }
     }
+}
 
-    async fn prepare(&mut self, idx: usize) -> Option<()> {
-        while !self.eof_known && idx >= self.block_offset + self.block_count {
+impl<R: AsyncRead + Unpin> SrcBuffer<R> {
+    /// Reads blocks until `end` blocks are loaded or the end of the source is reached.
+    fn poll_load(&mut self, cx: &mut Context, end: usize) -> Poll<Result<()>> {
+        if let Some(e) = self.error.take() {
+            return Poll::Ready(Err(e));
+        }
+        // a short read doesn't mean the end of the reader, only a read of zero bytes does
+        while !self.eof_known && self.loaded < end {
+            let count = self.slots.len();
+            let slot = &mut self.slots[self.loaded % count];
+            if Arc::get_mut(slot).is_none() {
+                // still lent, so the block it held is done with
+                debug_assert_eq!(self.partial, 0);
+                *slot = Arc::new(vec![0u8; BLKSIZE]);
+            }
+            let block = Arc::get_mut(slot).expect("slot not lent");
+            let block = &mut block[self.partial..];
+            let read_len = match Pin::new(&mut self.read).poll_read(cx, block) {
+                Poll::Ready(Ok(n)) => n,
+                Poll::Ready(Err(e)) if e.kind() == ErrorKind::Interrupted => continue,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            };
             debug!(
-                "prepare idx={}, block_offset={}, block_count={}",
-                idx, self.block_offset, self.block_count
+                "block={}, partial={}, read_len={}",
+                self.loaded, self.partial, read_len
             );
-            let eof = self.fetch().await?;
-            if eof {
+            self.read_len += read_len;
+            self.partial += read_len;
+            if read_len == 0 {
                 debug!("eof");
                 self.eof_known = true;
-                break;
+            }
+            if self.partial == BLKSIZE || (self.eof_known && self.partial > 0) {
+                self.loaded += 1;
+                self.partial = 0;
             }
         }
-        Some(())
+        Poll::Ready(Ok(()))
     }
+}
 
-    fn block_range(&self, idx: usize) -> Range<usize> {
-        debug!("idx={}, offset={}", idx, self.block_offset);
-        assert!(idx >= self.block_offset && idx < self.block_offset + self.block_count);
-
-        let idx = idx % self.block_count;
-        let start = (self.src.blksize as usize) * idx;
-        let end = (self.src.blksize as usize) * (idx + 1);
+impl<R: AsyncRead + Unpin> Blocks for SrcBuffer<R> {
+    fn poll_block(&mut self, cx: &mut Context, number: u64) -> Poll<Result<Block>> {
+        let blkno = usize::try_from(number).unwrap_or(usize::MAX);
+        self.wanted = self.wanted.max(blkno.saturating_add(1));
+        ready!(self.poll_load(cx, blkno.saturating_add(1)))?;
 
-        let start = start.min(self.read_len);
-        let end = end.min(self.read_len);
+        let data = if blkno >= self.loaded {
+            Slice::from_vec(Vec::new())
+        } else {
+            // the slot of the oldest block is reused once the next block starts coming in
+            let count = self.slots.len();
+            let first = (self.loaded + (self.partial > 0) as usize).saturating_sub(count);
+            if blkno < first {
+                return Poll::Ready(Err(std::io::Error::new(
+                    ErrorKind::InvalidInput,
+                    "source block is no longer buffered",
+                )));
+            }
+            let slot = &self.slots[blkno % count];
+            let len = BLKSIZE.min(self.read_len - blkno * BLKSIZE);
+            Slice::new(&slot[..len], slot.clone())
+        };
+        let size = if self.eof_known {
+            Some(self.read_len as u64)
+        } else {
+            None
+        };
+        Poll::Ready(Ok(block(number, data, size)))
+    }
 
-        start..end
+    fn poll_read_ahead(&mut self, cx: &mut Context, blocks: usize) {
+        // only into the spare slots, the others hold blocks copies may still go back to
+        let end = self.wanted + blocks.min(self.slots.len() - BLOCK_COUNT - 1);
+        if self.error.is_none() {
+            if let Poll::Ready(Err(e)) = self.poll_load(cx, end) {
+                self.error = Some(e);
+            }
+        }
     }
+}
 
-    async fn getblk(&mut self) {
-        debug!(
-            "getsrcblk: curblkno={}, getblkno={}",
-            self.src.curblkno, self.src.getblkno,
-        );
+/// Copies the blocks of an [`AsyncSource`], which only lends them until it is used again.
+struct Copied<S>(S);
 
-        let blkno = self.src.getblkno as usize;
-        self.prepare(blkno).await;
-        let range = self.block_range(blkno);
+impl<S: AsyncSource + Unpin> Blocks for Copied<S> {
+    fn poll_block(&mut self, cx: &mut Context, blkno: u64) -> Poll<Result<Block>> {
+        let data = ready!(Pin::new(&mut self.0).poll_block(cx, blkno, BLKSIZE))?;
+        // xdelta3 takes the length of a block for granted
+        let data = Slice::from_vec(data[..data.len().min(BLKSIZE)].to_vec());
+        Poll::Ready(Ok(block(blkno, data, self.0.size())))
+    }
 
-        let src = &mut self.src;
-        let data = &self.buf[range];
+    fn poll_read_ahead(&mut self, cx: &mut Context, blocks: usize) {
+        Pin::new(&mut self.0).poll_read_ahead(cx, BLKSIZE, blocks);
+    }
+}
 
-        src.curblkno = src.getblkno;
-        src.curblk = data.as_ptr();
-        src.onblk = data.len() as u32;
+/// Options shared by the streaming encoder and decoder.
+///
+/// ```
+/// use std::sync::Arc;
+/// use xdelta3::stream::{encode_async_with_config, Config};
+///
+/// let config = Config::new().allocator(Arc::new(std::alloc::System));
+/// let mut out = Vec::new();
+/// futures::executor::block_on(encode_async_with_config(
+///     &[1u8, 2, 3][..],
+///     &[1u8, 2, 4][..],
+///     &mut out,
+///     &config,
+/// ))
+/// .unwrap();
+/// ```
+#[derive(Clone)]
+pub struct Config {
+    pub(crate) allocator: Option<Allocator>,
+    read_ahead: usize,
+    limits: Limits,
+}
 
-        src.eof_known = self.eof_known as i32;
-        if !self.eof_known {
-            src.max_blkno = src.curblkno;
-            src.onlastblk = src.onblk;
-        } else {
-            src.max_blkno = (self.block_offset + self.block_count - 1) as u64;
-            src.onlastblk = (self.read_len % src.blksize as usize) as u32;
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            allocator: None,
+            read_ahead: DEFAULT_READ_AHEAD,
+            limits: Limits::default(),
         }
     }
 }
 
-struct Xd3Stream {
-    inner: binding::xd3_stream,
-}
-impl Xd3Stream {
-    fn new() -> Self {
-        let inner: binding::xd3_stream = unsafe { std::mem::zeroed() };
-        return Self { inner };
+impl Config {
+    pub fn new() -> Self {
+        Self::default()
     }
-}
-impl Drop for Xd3Stream {
-    fn drop(&mut self) {
-        unsafe {
-            binding::xd3_free_stream(&mut self.inner as *mut _);
-        }
+
+    /// Load up to `blocks` source blocks past the last one used while xdelta3 is busy,
+    /// so that sequential reads of the source don't wait on I/O. `0` turns it off.
+    ///
+    /// The default is 4 blocks of 1 MiB, and at most 32 blocks are read ahead. They are
+    /// buffered on top of the 64 blocks kept for copies to go back to.
+    pub fn read_ahead(mut self, blocks: usize) -> Self {
+        self.read_ahead = blocks;
+        self
+    }
+
+    /// Check patches against `limits` while decoding them, failing with
+    /// [`Error::LimitExceeded`] before xdelta3 decodes a window going past them.
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Route every allocation made by xdelta3 through `allocator`.
+    ///
+    /// By default the global allocator of the program is used.
+    ///
+    /// This only applies to the functions taking a `Config`. The functions working on slices,
+    /// such as [`encode`](crate::encode), [`decode`](crate::decode) and
+    /// [`EncoderContext`](crate::EncoderContext), take no allocator and leave xdelta3 to
+    /// `malloc`.
+    pub fn allocator(mut self, allocator: Arc<dyn GlobalAlloc + Send + Sync>) -> Self {
+        self.allocator = Some(allocator);
+        self
     }
 }
 
@@ -147,7 +244,19 @@ where
     R2: AsyncRead + Unpin,
     W: AsyncWrite + Unpin,
 {
-    process_async(Mode::Decode, input, src, out).await
+    let progress = Progress::default();
+    process_async(
+        Mode::Decode,
+        ReadAhead::new(input),
+        SrcBuffer::new(src, DEFAULT_READ_AHEAD),
+        out,
+        &Config::default(),
+        progress,
+        NO_CHECKPOINTS,
+    )
+    .await
+    .ok()
+    .map(drop)
 }
 
 pub async fn encode_async<R1, R2, W>(input: R1, src: R2, out: W) -> Option<()>
@@ -156,115 +265,987 @@ where
     R2: AsyncRead + Unpin,
     W: AsyncWrite + Unpin,
 {
-    process_async(Mode::Encode, input, src, out).await
+    let progress = Progress::default();
+    process_async(
+        Mode::Encode,
+        ReadAhead::new(input),
+        SrcBuffer::new(src, DEFAULT_READ_AHEAD),
+        out,
+        &Config::default(),
+        progress,
+        NO_CHECKPOINTS,
+    )
+    .await
+    .ok()
+    .map(drop)
+}
+
+/// Same as [`decode_async`], using the options from `config` and telling what went wrong.
+pub async fn decode_async_with_config<R1, R2, W>(
+    input: R1,
+    src: R2,
+    out: W,
+    config: &Config,
+) -> std::result::Result<(), Error>
+where
+    R1: AsyncRead + Unpin,
+    R2: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let progress = Progress::default();
+    process_async(
+        Mode::Decode,
+        ReadAhead::new(input),
+        SrcBuffer::new(src, config.read_ahead),
+        out,
+        config,
+        progress,
+        NO_CHECKPOINTS,
+    )
+    .await
+    .map(drop)
+}
+
+/// Same as [`encode_async`], using the options from `config` and telling what went wrong.
+pub async fn encode_async_with_config<R1, R2, W>(
+    input: R1,
+    src: R2,
+    out: W,
+    config: &Config,
+) -> std::result::Result<(), Error>
+where
+    R1: AsyncRead + Unpin,
+    R2: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let progress = Progress::default();
+    process_async(
+        Mode::Encode,
+        ReadAhead::new(input),
+        SrcBuffer::new(src, config.read_ahead),
+        out,
+        config,
+        progress,
+        NO_CHECKPOINTS,
+    )
+    .await
+    .map(drop)
+}
+
+/// Same as [`decode_async_with_config`], with the source given by a [`AsyncSource`].
+pub async fn decode_async_with_source<R, S, W>(
+    input: R,
+    source: S,
+    out: W,
+    config: &Config,
+) -> std::result::Result<(), Error>
+where
+    R: AsyncRead + Unpin,
+    S: AsyncSource + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let progress = Progress::default();
+    process_async(
+        Mode::Decode,
+        ReadAhead::new(input),
+        Copied(source),
+        out,
+        config,
+        progress,
+        NO_CHECKPOINTS,
+    )
+    .await
+    .map(drop)
+}
+
+/// Same as [`encode_async_with_config`], with the source given by a [`AsyncSource`].
+pub async fn encode_async_with_source<R, S, W>(
+    input: R,
+    source: S,
+    out: W,
+    config: &Config,
+) -> std::result::Result<(), Error>
+where
+    R: AsyncRead + Unpin,
+    S: AsyncSource + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let progress = Progress::default();
+    process_async(
+        Mode::Encode,
+        ReadAhead::new(input),
+        Copied(source),
+        out,
+        config,
+        progress,
+        NO_CHECKPOINTS,
+    )
+    .await
+    .map(drop)
+}
+
+/// Same as [`decode_async_with_config`], handing the buffer of `input` to xdelta3 as it is.
+///
+/// This saves copying the patch into a buffer of its own. xdelta3 still copies what it needs
+/// when a window spans two fills of the buffer.
+///
+/// xdelta3 reads the buffer from a thread of its own, which holds on to `input` until it is
+/// done with it, so `input` has to be owned.
+pub async fn decode_async_buffered<R1, R2, W>(
+    input: R1,
+    src: R2,
+    out: W,
+    config: &Config,
+) -> std::result::Result<(), Error>
+where
+    R1: AsyncBufRead + Unpin + Send + 'static,
+    R2: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let progress = Progress::default();
+    process_async(
+        Mode::Decode,
+        Lend::new(input),
+        SrcBuffer::new(src, config.read_ahead),
+        out,
+        config,
+        progress,
+        NO_CHECKPOINTS,
+    )
+    .await
+    .map(drop)
+}
+
+/// Same as [`encode_async_with_config`], handing the buffer of `input` to xdelta3 as it is.
+///
+/// This saves copying the new data into a buffer of its own. xdelta3 still copies what it needs
+/// when a window spans two fills of the buffer.
+///
+/// xdelta3 reads the buffer from a thread of its own, which holds on to `input` until it is
+/// done with it, so `input` has to be owned.
+pub async fn encode_async_buffered<R1, R2, W>(
+    input: R1,
+    src: R2,
+    out: W,
+    config: &Config,
+) -> std::result::Result<(), Error>
+where
+    R1: AsyncBufRead + Unpin + Send + 'static,
+    R2: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let progress = Progress::default();
+    process_async(
+        Mode::Encode,
+        Lend::new(input),
+        SrcBuffer::new(src, config.read_ahead),
+        out,
+        config,
+        progress,
+        NO_CHECKPOINTS,
+    )
+    .await
+    .map(drop)
 }
 
-enum Mode {
-    Encode,
-    Decode,
+/// Decodes the patch coming from `input` against `source`, returning the output as a stream.
+///
+/// The chunks are those produced by xdelta3 as it goes, so the output can be passed on,
+/// for instance as the body of an HTTP response, before the input has been read in full.
+/// The returned stream does the work as it is polled.
+pub fn decode_stream<I, B, S>(
+    input: I,
+    source: S,
+    config: &Config,
+) -> impl Stream<Item = std::result::Result<Bytes, Error>>
+where
+    I: Stream<Item = Result<B>> + Unpin + Send + 'static,
+    B: AsRef<[u8]> + Send + 'static,
+    S: AsyncSource + Unpin,
+{
+    process_stream(Mode::Decode, input, source, config)
 }
 
-async fn process_async<R1, R2, W>(mode: Mode, mut input: R1, src: R2, mut out: W) -> Option<()>
+/// Encodes the new data coming from `input` against `source`, returning the output as a stream.
+///
+/// The chunks are those produced by xdelta3 as it goes, so the output can be passed on,
+/// for instance as the body of an HTTP response, before the input has been read in full.
+/// The returned stream does the work as it is polled.
+pub fn encode_stream<I, B, S>(
+    input: I,
+    source: S,
+    config: &Config,
+) -> impl Stream<Item = std::result::Result<Bytes, Error>>
+where
+    I: Stream<Item = Result<B>> + Unpin + Send + 'static,
+    B: AsRef<[u8]> + Send + 'static,
+    S: AsyncSource + Unpin,
+{
+    process_stream(Mode::Encode, input, source, config)
+}
+
+/// Position of the decoder after a complete window.
+///
+/// VCDIFF windows can be decoded independently of each other, so a decode interrupted
+/// after a checkpoint has been reported can be continued from there with
+/// [`resume_from_async`] or [`resume_from`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Checkpoint {
+    /// Number of patch bytes consumed.
+    pub patch_offset: u64,
+    /// Number of target bytes written.
+    pub target_offset: u64,
+    /// Number of windows decoded.
+    pub window: u64,
+}
+
+/// Same as [`decode_async_with_config`], calling `on_checkpoint` after every window.
+///
+/// The output is flushed before each checkpoint is reported.
+pub async fn decode_async_with_checkpoints<R1, R2, W, F>(
+    input: R1,
+    src: R2,
+    out: W,
+    config: &Config,
+    on_checkpoint: F,
+) -> std::result::Result<(), Error>
 where
     R1: AsyncRead + Unpin,
     R2: AsyncRead + Unpin,
     W: AsyncWrite + Unpin,
+    F: FnMut(Checkpoint),
+{
+    let progress = Progress::default();
+    process_async(
+        Mode::Decode,
+        ReadAhead::new(input),
+        SrcBuffer::new(src, config.read_ahead),
+        out,
+        config,
+        progress,
+        Some(on_checkpoint),
+    )
+    .await
+    .map(drop)
+}
+
+/// Continues a decode interrupted after `checkpoint`.
+///
+/// `input` and `out` must be the patch and the partially written target of the interrupted
+/// decode; they are seeked to the position recorded in the checkpoint. `src` is read from
+/// its beginning. New checkpoints are reported to `on_checkpoint` as with
+/// [`decode_async_with_checkpoints`].
+pub async fn resume_from_async<R1, R2, W, F>(
+    checkpoint: Checkpoint,
+    mut input: R1,
+    src: R2,
+    mut out: W,
+    config: &Config,
+    on_checkpoint: F,
+) -> std::result::Result<(), Error>
+where
+    R1: AsyncRead + AsyncSeek + Unpin,
+    R2: AsyncRead + Unpin,
+    W: AsyncWrite + AsyncSeek + Unpin,
+    F: FnMut(Checkpoint),
 {
-    let mut stream = Xd3Stream::new();
-    let stream = &mut stream.inner;
-    let mut cfg: binding::xd3_config = unsafe { std::mem::zeroed() };
-    cfg.winsize = XD3_DEFAULT_WINSIZE as u32;
+    // the windows left need the file header in front of them
+    let header = read_header(&mut input).await?;
+    let header_len = header.len();
+    debug!("resume: header_len={}, {:?}", header_len, checkpoint);
 
-    let mut src_buf = SrcBuffer::new(src).await?;
+    let (progress, header) = if checkpoint.patch_offset <= header_len as u64 {
+        (Progress::default(), &[][..])
+    } else {
+        let progress = Progress {
+            skipped: checkpoint.patch_offset - header_len as u64,
+            target_offset: checkpoint.target_offset,
+            window: checkpoint.window,
+            checksummed: 0,
+        };
+        (progress, &header[..header_len])
+    };
+    input
+        .seek(SeekFrom::Start(progress.skipped + header.len() as u64))
+        .await?;
+    out.seek(SeekFrom::Start(progress.target_offset)).await?;
+
+    let input = header.chain(input);
+    process_async(
+        Mode::Decode,
+        ReadAhead::new(input),
+        SrcBuffer::new(src, config.read_ahead),
+        out,
+        config,
+        progress,
+        Some(on_checkpoint),
+    )
+    .await
+    .map(drop)
+}
+
+/// Checks that `input` applies to `src` without writing the target, as
+/// [`verify`](crate::verify) does for slices.
+pub async fn verify_async<R1, R2>(
+    input: R1,
+    src: R2,
+    config: &Config,
+) -> std::result::Result<Verification, Error>
+where
+    R1: AsyncRead + Unpin,
+    R2: AsyncRead + Unpin,
+{
+    let progress = process_async(
+        Mode::Decode,
+        ReadAhead::new(input),
+        SrcBuffer::new(src, config.read_ahead),
+        futures_util::io::sink(),
+        config,
+        Progress::default(),
+        NO_CHECKPOINTS,
+    )
+    .await?;
+    Ok(Verification {
+        target_len: progress.target_offset,
+        windows: progress.window,
+        checksummed_windows: progress.checksummed,
+    })
+}
+
+/// Builds the index of a patch, reading only the headers of its windows.
+pub async fn index_async<R>(mut patch: R) -> std::result::Result<PatchIndex, Error>
+where
+    R: AsyncRead + AsyncSeek + Unpin,
+{
+    let mut index = PatchIndex {
+        header_len: read_header(&mut patch).await?.len() as u64,
+        ..Default::default()
+    };
+
+    let mut offset = index.header_len;
+    loop {
+        patch.seek(SeekFrom::Start(offset)).await?;
+        // large enough for the fields up to the target window length
+        let mut buf = Vec::with_capacity(64);
+        (&mut patch).take(64).read_to_end(&mut buf).await?;
+        if buf.is_empty() {
+            return Ok(index);
+        }
+        let window = vcdiff::parse_window_header(&buf, index.target_len())?
+            .ok_or(Error::InvalidPatch("truncated window"))?;
+        index.push(offset, &window);
+        offset += window.len as u64;
+    }
+}
 
-    let ret = unsafe { binding::xd3_config_stream(stream, &mut cfg) };
-    if ret != 0 {
-        return None;
+/// Decodes only `range` of the target, using the `index` of `patch`.
+///
+/// Only the windows overlapping the range are read from `patch`, and only the bytes in
+/// the range are written to `out`.
+pub async fn decode_range_async<R1, R2, W>(
+    index: &PatchIndex,
+    mut patch: R1,
+    src: R2,
+    out: W,
+    range: Range<u64>,
+    config: &Config,
+) -> std::result::Result<(), Error>
+where
+    R1: AsyncRead + AsyncSeek + Unpin,
+    R2: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let windows = index.windows_for(range.clone());
+    if windows.is_empty() {
+        return Ok(());
     }
 
-    let ret = unsafe { binding::xd3_set_source(stream, &mut src_buf.src) };
-    if ret != 0 {
-        return None;
+    let mut header = vec![0u8; index.header_len as usize];
+    patch.seek(SeekFrom::Start(0)).await?;
+    patch.read_exact(&mut header).await?;
+    let body = index.patch_range(windows.clone());
+    patch.seek(SeekFrom::Start(body.start)).await?;
+    let input = (&header[..]).chain(patch.take(body.end - body.start));
+
+    let base = index.windows[windows.start].target_offset;
+    let end = range.end.min(index.target_len());
+    let out = RangeWriter {
+        inner: out,
+        skip: range.start.saturating_sub(base),
+        left: end - range.start.max(base),
+    };
+    let progress = Progress::default();
+    process_async(
+        Mode::Decode,
+        ReadAhead::new(input),
+        SrcBuffer::new(src, config.read_ahead),
+        out,
+        config,
+        progress,
+        NO_CHECKPOINTS,
+    )
+    .await
+    .map(drop)
+}
+
+/// Reads the file header at the start of a patch.
+async fn read_header<R>(input: &mut R) -> std::result::Result<Vec<u8>, Error>
+where
+    R: AsyncRead + AsyncSeek + Unpin,
+{
+    input.seek(SeekFrom::Start(0)).await?;
+    let mut header = Vec::new();
+    loop {
+        let mut buf = [0u8; 256];
+        let n = input.read(&mut buf).await?;
+        header.extend_from_slice(&buf[..n]);
+        if let Some(h) = vcdiff::parse_header_prefix(&header)? {
+            header.truncate(h.len);
+            return Ok(header);
+        }
+        if n == 0 {
+            return Err(Error::InvalidPatch("truncated header"));
+        }
     }
+}
 
-    let input_buf_size = stream.winsize as usize;
-    debug!("stream.winsize={}", input_buf_size);
-    let mut input_buf = Vec::with_capacity(input_buf_size);
-    input_buf.resize(input_buf_size, 0u8);
-    let mut eof = false;
+/// Passes on the bytes in a range of what is written to it, discarding the rest.
+struct RangeWriter<W> {
+    inner: W,
+    skip: u64,
+    left: u64,
+}
 
-    'outer: while !eof {
-        let read_size = match input.read(&mut input_buf).await {
-            Ok(n) => n,
-            Err(_e) => {
-                debug!("error on read: {:?}", _e);
-                return None;
+impl<W: AsyncWrite + Unpin> AsyncWrite for RangeWriter<W> {
+    fn poll_write(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context,
+        buf: &[u8],
+    ) -> std::task::Poll<Result<usize>> {
+        let this = self.get_mut();
+        if this.skip > 0 {
+            let n = this.skip.min(buf.len() as u64);
+            this.skip -= n;
+            return std::task::Poll::Ready(Ok(n as usize));
+        }
+        if this.left == 0 {
+            return std::task::Poll::Ready(Ok(buf.len()));
+        }
+        let buf = &buf[..this.left.min(buf.len() as u64) as usize];
+        let r = std::pin::Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let std::task::Poll::Ready(Ok(n)) = r {
+            this.left -= n as u64;
+        }
+        r
+    }
+
+    fn poll_flush(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context,
+    ) -> std::task::Poll<Result<()>> {
+        std::pin::Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_close(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context,
+    ) -> std::task::Poll<Result<()>> {
+        std::pin::Pin::new(&mut self.get_mut().inner).poll_close(cx)
+    }
+}
+
+fn process_stream<I, B, S>(
+    mode: Mode,
+    input: I,
+    source: S,
+    config: &Config,
+) -> impl Stream<Item = std::result::Result<Bytes, Error>>
+where
+    I: Stream<Item = Result<B>> + Unpin + Send + 'static,
+    B: AsRef<[u8]> + Send + 'static,
+    S: AsyncSource + Unpin,
+{
+    let (tx, rx) = mpsc::channel(1);
+    let config = config.clone();
+    let process = async move {
+        let out = ChannelWriter { tx: tx.clone() };
+        let input = Lend::new(input.into_async_read());
+        let progress = Progress::default();
+        let r = process_async(
+            mode,
+            input,
+            Copied(source),
+            out,
+            &config,
+            progress,
+            NO_CHECKPOINTS,
+        )
+        .await;
+        if let Err(e) = r {
+            let _ = tx.clone().try_send(Err(e));
+        }
+    };
+    Drive {
+        process: Some(Box::pin(process)),
+        rx,
+    }
+}
+
+/// Sends what is written to it down a channel.
+struct ChannelWriter {
+    tx: mpsc::Sender<std::result::Result<Bytes, Error>>,
+}
+
+impl AsyncWrite for ChannelWriter {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<Result<usize>> {
+        let tx = &mut self.get_mut().tx;
+        if ready!(tx.poll_ready(cx)).is_err()
+            || tx.start_send(Ok(Bytes::copy_from_slice(buf))).is_err()
+        {
+            return Poll::Ready(Err(ErrorKind::BrokenPipe.into()));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Stream of the output of `process`, which is run as the stream is polled.
+struct Drive<F> {
+    process: Option<Pin<Box<F>>>,
+    rx: mpsc::Receiver<std::result::Result<Bytes, Error>>,
+}
+
+impl<F: std::future::Future<Output = ()>> Stream for Drive<F> {
+    type Item = std::result::Result<Bytes, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if let Some(process) = this.process.as_mut() {
+            if process.as_mut().poll(cx).is_ready() {
+                this.process = None;
             }
+        }
+        // ends once `process` is done and has dropped its senders
+        Pin::new(&mut this.rx).poll_next(cx)
+    }
+}
+
+/// Blocking version of [`decode_async_with_config`] and [`encode_async_with_config`].
+pub(crate) fn process<R1, R2, W>(
+    mode: Mode,
+    input: R1,
+    src: R2,
+    out: W,
+    config: &Config,
+) -> std::result::Result<(), Error>
+where
+    R1: std::io::Read,
+    R2: std::io::Read,
+    W: std::io::Write,
+{
+    futures_executor::block_on(process_async(
+        mode,
+        ReadAhead::new(AllowStdIo::new(input)),
+        SrcBuffer::new(AllowStdIo::new(src), config.read_ahead),
+        AllowStdIo::new(out),
+        config,
+        Progress::default(),
+        NO_CHECKPOINTS,
+    ))
+    .map(drop)
+}
+
+/// Blocking version of [`decode_async_with_checkpoints`].
+pub fn decode_with_checkpoints<R1, R2, W, F>(
+    input: R1,
+    src: R2,
+    out: W,
+    config: &Config,
+    on_checkpoint: F,
+) -> std::result::Result<(), Error>
+where
+    R1: std::io::Read,
+    R2: std::io::Read,
+    W: std::io::Write,
+    F: FnMut(Checkpoint),
+{
+    futures_executor::block_on(decode_async_with_checkpoints(
+        AllowStdIo::new(input),
+        AllowStdIo::new(src),
+        AllowStdIo::new(out),
+        config,
+        on_checkpoint,
+    ))
+}
+
+/// Blocking version of [`resume_from_async`].
+pub fn resume_from<R1, R2, W, F>(
+    checkpoint: Checkpoint,
+    input: R1,
+    src: R2,
+    out: W,
+    config: &Config,
+    on_checkpoint: F,
+) -> std::result::Result<(), Error>
+where
+    R1: std::io::Read + std::io::Seek,
+    R2: std::io::Read,
+    W: std::io::Write + std::io::Seek,
+    F: FnMut(Checkpoint),
+{
+    futures_executor::block_on(resume_from_async(
+        checkpoint,
+        AllowStdIo::new(input),
+        AllowStdIo::new(src),
+        AllowStdIo::new(out),
+        config,
+        on_checkpoint,
+    ))
+}
+
+/// Blocking version of [`decode_async_with_source`].
+pub fn decode_with_source<R, S, W>(
+    input: R,
+    source: S,
+    out: W,
+    config: &Config,
+) -> std::result::Result<(), Error>
+where
+    R: std::io::Read,
+    S: Source + Unpin,
+    W: std::io::Write,
+{
+    futures_executor::block_on(decode_async_with_source(
+        AllowStdIo::new(input),
+        source,
+        AllowStdIo::new(out),
+        config,
+    ))
+}
+
+/// Blocking version of [`encode_async_with_source`].
+pub fn encode_with_source<R, S, W>(
+    input: R,
+    source: S,
+    out: W,
+    config: &Config,
+) -> std::result::Result<(), Error>
+where
+    R: std::io::Read,
+    S: Source + Unpin,
+    W: std::io::Write,
+{
+    futures_executor::block_on(encode_async_with_source(
+        AllowStdIo::new(input),
+        source,
+        AllowStdIo::new(out),
+        config,
+    ))
+}
+
+/// Blocking version of [`decode_async_buffered`].
+pub fn decode_buffered<R1, R2, W>(
+    input: R1,
+    src: R2,
+    out: W,
+    config: &Config,
+) -> std::result::Result<(), Error>
+where
+    R1: std::io::BufRead + Send + 'static,
+    R2: std::io::Read,
+    W: std::io::Write,
+{
+    futures_executor::block_on(decode_async_buffered(
+        AllowStdIo::new(input),
+        AllowStdIo::new(src),
+        AllowStdIo::new(out),
+        config,
+    ))
+}
+
+/// Blocking version of [`encode_async_buffered`].
+pub fn encode_buffered<R1, R2, W>(
+    input: R1,
+    src: R2,
+    out: W,
+    config: &Config,
+) -> std::result::Result<(), Error>
+where
+    R1: std::io::BufRead + Send + 'static,
+    R2: std::io::Read,
+    W: std::io::Write,
+{
+    futures_executor::block_on(encode_async_buffered(
+        AllowStdIo::new(input),
+        AllowStdIo::new(src),
+        AllowStdIo::new(out),
+        config,
+    ))
+}
+
+/// Where a decode starts, when it doesn't start at the beginning of the patch, and where
+/// it ended up.
+#[derive(Default)]
+struct Progress {
+    // patch bytes left out between the file header and the input
+    skipped: u64,
+    target_offset: u64,
+    window: u64,
+    // windows whose Adler-32 was checked
+    checksummed: u64,
+}
+
+const NO_CHECKPOINTS: Option<fn(Checkpoint)> = None;
+
+/// Hands the input over to the worker.
+trait Input {
+    /// Makes progress on reading ahead, while the worker is busy.
+    fn poll_ahead(&mut self, _cx: &mut Context) {}
+
+    /// Returns the next chunk of input for the worker. An empty chunk ends the input.
+    fn poll_chunk(&mut self, cx: &mut Context) -> Poll<Result<Request>>;
+
+    /// Takes back the previous chunk, which the worker is done with.
+    fn recycle(&mut self, prev: Option<Vec<u8>>);
+}
+
+/// Hands source blocks over to the worker.
+trait Blocks {
+    /// Returns block `blkno` for the worker, which holds on to it until it is done with it.
+    fn poll_block(&mut self, cx: &mut Context, blkno: u64) -> Poll<Result<Block>>;
+
+    /// Makes progress on loading `blocks` blocks ahead, while the worker is busy.
+    fn poll_read_ahead(&mut self, cx: &mut Context, blocks: usize);
+}
+
+/// Reads the input into buffers of its own, one chunk ahead of the worker.
+struct ReadAhead<R> {
+    read: R,
+    spare: Vec<Vec<u8>>,
+    // buffer of a read that is pending
+    reading: Option<Vec<u8>>,
+    ahead: Option<Result<(Vec<u8>, usize)>>,
+    eof: bool,
+}
+
+impl<R: AsyncRead + Unpin> ReadAhead<R> {
+    fn new(read: R) -> Self {
+        Self {
+            read,
+            spare: Vec::new(),
+            reading: None,
+            ahead: None,
+            eof: false,
+        }
+    }
+
+    fn poll_read_chunk(&mut self, cx: &mut Context) -> Poll<Result<(Vec<u8>, usize)>> {
+        let mut buf = self
+            .reading
+            .take()
+            .or_else(|| self.spare.pop())
+            .unwrap_or_else(|| vec![0u8; XD3_DEFAULT_WINSIZE]);
+        match Pin::new(&mut self.read).poll_read(cx, &mut buf) {
+            Poll::Ready(r) => Poll::Ready(r.map(|n| (buf, n))),
+            Poll::Pending => {
+                self.reading = Some(buf);
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> Input for ReadAhead<R> {
+    fn poll_ahead(&mut self, cx: &mut Context) {
+        if self.ahead.is_none() && !self.eof {
+            if let Poll::Ready(r) = self.poll_read_chunk(cx) {
+                self.ahead = Some(r);
+            }
+        }
+    }
+
+    fn poll_chunk(&mut self, cx: &mut Context) -> Poll<Result<Request>> {
+        let chunk = match self.ahead.take() {
+            Some(r) => r,
+            None => ready!(self.poll_read_chunk(cx)),
         };
-        debug!("read_size={}", read_size);
-        if read_size == 0 {
-            // xd3_set_flags
-            stream.flags = binding::xd3_flags::XD3_FLUSH as i32;
-            eof = true;
+        Poll::Ready(chunk.map(|(buf, n)| {
+            debug!("read_size={}", n);
+            self.eof = n == 0;
+            Request::Input(buf, n)
+        }))
+    }
+
+    fn recycle(&mut self, prev: Option<Vec<u8>>) {
+        self.spare.extend(prev);
+    }
+}
+
+/// Lends the buffer of an `AsyncBufRead` to the worker, instead of copying it.
+///
+/// The reader isn't touched until the worker asks for more input. It is shared with the
+/// worker, which keeps it alive for as long as it may read the lent buffer, so the buffer
+/// outlives its use even when the decode is dropped half-way.
+struct Lend<R> {
+    read: Arc<Mutex<R>>,
+    lent: usize,
+}
+
+impl<R> Lend<R> {
+    fn new(read: R) -> Self {
+        Self {
+            read: Arc::new(Mutex::new(read)),
+            lent: 0,
         }
+    }
+}
 
-        // xd3_avail_input
-        stream.next_in = input_buf.as_ptr();
-        stream.avail_in = read_size as u32;
+impl<R: AsyncBufRead + Unpin + Send + 'static> Input for Lend<R> {
+    fn poll_chunk(&mut self, cx: &mut Context) -> Poll<Result<Request>> {
+        let mut read = self.read.lock().unwrap_or_else(PoisonError::into_inner);
+        let buf = ready!(Pin::new(&mut *read).poll_fill_buf(cx))?;
+        debug!("lent={}", buf.len());
+        self.lent = buf.len();
+        let slice = Slice::new(buf, self.read.clone());
+        Poll::Ready(Ok(Request::Slice(slice)))
+    }
 
-        'inner: loop {
-            let ret: binding::xd3_rvalues = unsafe {
-                std::mem::transmute(match mode {
-                    Mode::Encode => binding::xd3_encode_input(stream),
-                    Mode::Decode => binding::xd3_decode_input(stream),
-                })
-            };
+    fn recycle(&mut self, _prev: Option<Vec<u8>>) {
+        let mut read = self.read.lock().unwrap_or_else(PoisonError::into_inner);
+        Pin::new(&mut *read).consume(self.lent);
+        self.lent = 0;
+    }
+}
 
-            if stream.msg != std::ptr::null() {
-                debug!("ret={:?}, msg={:?}", ret, unsafe {
-                    std::ffi::CStr::from_ptr(stream.msg)
-                },);
-            } else {
-                debug!("ret={:?}", ret,);
+async fn process_async<I, S, W, F>(
+    mode: Mode,
+    mut input: I,
+    mut source: S,
+    mut out: W,
+    config: &Config,
+    mut progress: Progress,
+    mut on_checkpoint: Option<F>,
+) -> std::result::Result<Progress, Error>
+where
+    I: Input,
+    S: Blocks,
+    W: AsyncWrite + Unpin,
+    F: FnMut(Checkpoint),
+{
+    let mut worker = Worker::spawn(mode, config, BLKSIZE, XD3_DEFAULT_SRCWINSZ);
+
+    // number of bytes passed on to the worker
+    let mut input_offset = 0u64;
+    let mut limits = match mode {
+        Mode::Decode if !config.limits.is_unlimited() => Some(LimitCheck::new(config.limits)),
+        _ => None,
+    };
+
+    loop {
+        let event = poll_fn(|cx| {
+            input.poll_ahead(cx);
+            if config.read_ahead > 0 {
+                source.poll_read_ahead(cx, config.read_ahead);
             }
+            Pin::new(&mut worker.events).poll_next(cx)
+        })
+        .await;
 
-            use binding::xd3_rvalues::*;
-            match ret {
-                XD3_INPUT => {
-                    continue 'outer;
-                    //
+        // the worker only goes away without a word if it panicked
+        let stopped = || Error::Xdelta3("worker stopped".to_owned());
+        match event.ok_or_else(stopped)? {
+            Event::Input(prev) => {
+                input.recycle(prev);
+                let request = poll_fn(|cx| input.poll_chunk(cx)).await.map_err(|e| {
+                    debug!("error on read: {:?}", e);
+                    e
+                })?;
+                if let Some(limits) = limits.as_mut() {
+                    limits.feed(request.input())?;
                 }
-                XD3_OUTPUT => {
-                    let mut out_data = unsafe {
-                        std::slice::from_raw_parts(stream.next_out, stream.avail_out as usize)
-                    };
-                    while !out_data.is_empty() {
-                        let n = match out.write(out_data).await {
-                            Ok(n) => n,
-                            Err(_e) => {
-                                debug!("error on write: {:?}", _e);
-                                return None;
-                            }
-                        };
-                        out_data = &out_data[n..];
-                    }
-
-                    // xd3_consume_output
-                    stream.avail_out = 0;
-                }
-                XD3_GETSRCBLK => {
-                    src_buf.getblk().await;
-                }
-                XD3_GOTHEADER | XD3_WINSTART | XD3_WINFINISH => {
-                    // do nothing
-                }
-                XD3_TOOFARBACK | XD3_INTERNAL | XD3_INVALID | XD3_INVALID_INPUT | XD3_NOSECOND
-                | XD3_UNIMPLEMENTED => {
-                    return None;
+                input_offset += request.input_len() as u64;
+                worker.send(request).ok_or_else(stopped)?;
+            }
+            Event::Output(data) => {
+                out.write_all(&data).await.map_err(|e| {
+                    debug!("error on write: {:?}", e);
+                    e
+                })?;
+                progress.target_offset += data.len() as u64;
+            }
+            Event::GetBlock(blkno) => {
+                let block = poll_fn(|cx| source.poll_block(cx, blkno))
+                    .await
+                    .map_err(|e| {
+                        debug!("error on source: {:?}", e);
+                        e
+                    })?;
+                worker.send(Request::Block(block)).ok_or_else(stopped)?;
+            }
+            Event::WinFinish {
+                avail_in,
+                checksummed,
+            } => {
+                progress.window += 1;
+                progress.checksummed += checksummed as u64;
+                if let Some(on_checkpoint) = on_checkpoint.as_mut() {
+                    out.flush().await?;
+                    on_checkpoint(Checkpoint {
+                        patch_offset: progress.skipped + input_offset - avail_in as u64,
+                        target_offset: progress.target_offset,
+                        window: progress.window,
+                    });
                 }
             }
+            Event::Done => break,
+            Event::Error(msg) => {
+                debug!("error: {}", msg);
+                return Err(Error::Xdelta3(msg));
+            }
         }
     }
 
-    out.flush().await.ok()
+    out.flush().await?;
+    Ok(progress)
+}
+
+/// Describes block `blkno` and where the source ends, as far as it is known.
+fn block(blkno: u64, data: Slice, size: Option<u64>) -> Block {
+    let blksize = BLKSIZE as u64;
+    // a short block is the last one
+    let size = size.or_else(|| {
+        if data.len() < BLKSIZE && (!data.is_empty() || blkno == 0) {
+            Some(
+                blkno
+                    .saturating_mul(blksize)
+                    .saturating_add(data.len() as u64),
+            )
+        } else {
+            None
+        }
+    });
+    match size {
+        Some(size) => {
+            let max_blkno = size.saturating_sub(1) / blksize;
+            Block {
+                data,
+                eof_known: true,
+                max_blkno,
+                onlastblk: (size - max_blkno * blksize) as u32,
+            }
+        }
+        None => Block {
+            onlastblk: data.len() as u32,
+            data,
+            eof_known: false,
+            max_blkno: blkno,
+        },
+    }
 }
@@ -0,0 +1,752 @@
+//! A parser for the VCDIFF format (RFC 3284) as written by xdelta3.
+//!
+//! This gives access to the structure of a patch (its header, its windows and the
+//! instructions inside them) without going through the C decoder. Patches using a
+//! secondary compressor can still be split into windows, but their instructions can
+//! only be read when the window sections are stored uncompressed. Application-defined
+//! code tables are not supported.
+
+use super::error::Error;
+
+const MAGIC: [u8; 4] = [0xd6, 0xc3, 0xc4, 0x00];
+
+// header indicator
+pub(crate) const VCD_SECONDARY: u8 = 0x01;
+pub(crate) const VCD_CODETABLE: u8 = 0x02;
+pub(crate) const VCD_APPHEADER: u8 = 0x04;
+
+// window indicator
+pub(crate) const VCD_SOURCE: u8 = 0x01;
+pub(crate) const VCD_TARGET: u8 = 0x02;
+pub(crate) const VCD_ADLER32: u8 = 0x04;
+
+// delta indicator
+pub(crate) const VCD_DATACOMP: u8 = 0x01;
+pub(crate) const VCD_INSTCOMP: u8 = 0x02;
+pub(crate) const VCD_ADDRCOMP: u8 = 0x04;
+
+// sizes of the address cache of the default code table
+const S_NEAR: usize = 4;
+const S_SAME: usize = 3;
+
+const TRUNCATED: &str = "unexpected end of patch";
+
+/// The file header of a patch.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Header<'a> {
+    /// Identifier of the secondary compressor used by the windows, if any.
+    pub secondary: Option<u8>,
+    /// Application data stored in the header (xdelta3 puts file names there).
+    pub app_header: Option<&'a [u8]>,
+    /// Number of bytes taken by the header at the start of the patch.
+    pub len: usize,
+}
+
+/// Which data a window copies from besides its own target.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SegmentKind {
+    /// The source (the "original" data).
+    Source,
+    /// The target data produced by earlier windows.
+    Target,
+}
+
+/// The part of the source or target a window is allowed to copy from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Segment {
+    pub kind: SegmentKind,
+    pub position: u64,
+    pub len: u64,
+}
+
+/// A window of a patch, which produces a contiguous range of the target.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Window<'a> {
+    /// Offset of the window in the patch.
+    pub offset: usize,
+    /// Number of bytes taken by the window in the patch.
+    pub len: usize,
+    /// Offset of the data produced by this window in the target.
+    pub target_offset: u64,
+    /// Number of bytes produced by this window.
+    pub target_len: u64,
+    pub segment: Option<Segment>,
+    /// Raw delta indicator, telling which sections are compressed.
+    pub delta_indicator: u8,
+    /// Adler-32 checksum of the window's target data, if the encoder stored one.
+    pub adler32: Option<u32>,
+    pub data: &'a [u8],
+    pub inst: &'a [u8],
+    pub addr: &'a [u8],
+}
+
+/// A single instruction of a window.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Instruction<'a> {
+    /// Append literal bytes.
+    Add(&'a [u8]),
+    /// Append `len` copies of `byte`.
+    Run { byte: u8, len: u64 },
+    /// Append `len` bytes found at `addr`.
+    ///
+    /// Addresses below the length of the window's segment point into the segment, the
+    /// others point into the target data of the window itself (minus the segment length).
+    Copy { addr: u64, len: u64 },
+}
+
+impl<'a> Instruction<'a> {
+    /// Number of target bytes produced by this instruction.
+    pub fn len(&self) -> u64 {
+        match self {
+            Instruction::Add(data) => data.len() as u64,
+            Instruction::Run { len, .. } | Instruction::Copy { len, .. } => *len,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// A parsed patch.
+///
+/// ```
+/// use xdelta3::vcdiff::{Instruction, Patch};
+///
+/// let patch = Patch::parse(&[214, 195, 196, 0, 0, 0, 13, 7, 0, 7, 1, 0, 1, 2, 3, 4, 5, 6, 7, 8]).unwrap();
+/// let window = patch.windows().next().unwrap().unwrap();
+/// let inst: Vec<_> = window.instructions().unwrap().collect::<Result<_, _>>().unwrap();
+/// assert_eq!(inst, vec![Instruction::Add(&[1, 2, 3, 4, 5, 6, 7])]);
+/// ```
+#[derive(Clone, Debug)]
+pub struct Patch<'a> {
+    data: &'a [u8],
+    header: Header<'a>,
+}
+
+impl<'a> Patch<'a> {
+    /// Parses the header of `data`. Windows are parsed lazily by [`Patch::windows`].
+    pub fn parse(data: &'a [u8]) -> Result<Self, Error> {
+        let header = parse_header(data)?;
+        Ok(Self { data, header })
+    }
+
+    pub fn header(&self) -> &Header<'a> {
+        &self.header
+    }
+
+    pub fn windows(&self) -> Windows<'a> {
+        Windows {
+            cursor: Cursor::new(self.data, self.header.len),
+            target_offset: 0,
+            failed: false,
+        }
+    }
+}
+
+/// Iterator over the windows of a [`Patch`].
+pub struct Windows<'a> {
+    cursor: Cursor<'a>,
+    target_offset: u64,
+    failed: bool,
+}
+
+impl<'a> Iterator for Windows<'a> {
+    type Item = Result<Window<'a>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed || self.cursor.is_empty() {
+            return None;
+        }
+        let window = parse_window(&mut self.cursor, self.target_offset).and_then(|w| {
+            self.target_offset = self
+                .target_offset
+                .checked_add(w.target_len)
+                .ok_or(Error::InvalidPatch("integer overflow"))?;
+            Ok(w)
+        });
+        self.failed = window.is_err();
+        Some(window)
+    }
+}
+
+/// Parses the file header at the start of `data`.
+pub fn parse_header(data: &[u8]) -> Result<Header<'_>, Error> {
+    let mut c = Cursor::new(data, 0);
+    if c.bytes(MAGIC.len())? != MAGIC {
+        return Err(Error::InvalidPatch("bad magic"));
+    }
+    let indicator = c.byte()?;
+    if indicator & !(VCD_SECONDARY | VCD_CODETABLE | VCD_APPHEADER) != 0 {
+        return Err(Error::InvalidPatch("unknown header indicator"));
+    }
+    let secondary = if indicator & VCD_SECONDARY != 0 {
+        Some(c.byte()?)
+    } else {
+        None
+    };
+    if indicator & VCD_CODETABLE != 0 {
+        return Err(Error::Unsupported("application-defined code table"));
+    }
+    let app_header = if indicator & VCD_APPHEADER != 0 {
+        let len = c.size()?;
+        Some(c.bytes(len)?)
+    } else {
+        None
+    };
+    Ok(Header {
+        secondary,
+        app_header,
+        len: c.pos,
+    })
+}
+
+/// Like [`parse_header`], but returns `None` when `data` is only the start of a header.
+pub(crate) fn parse_header_prefix(data: &[u8]) -> Result<Option<Header<'_>>, Error> {
+    match parse_header(data) {
+        Err(ref e) if is_truncated(e) => Ok(None),
+        r => r.map(Some),
+    }
+}
+
+fn is_truncated(e: &Error) -> bool {
+    match e {
+        Error::InvalidPatch(msg) => *msg == TRUNCATED,
+        _ => false,
+    }
+}
+
+/// The fields at the start of a window, enough to place it in the patch and the target.
+pub(crate) struct WindowHeader {
+    indicator: u8,
+    pub(crate) segment: Option<Segment>,
+    /// Number of bytes taken by the whole window in the patch.
+    pub(crate) len: usize,
+    pub(crate) target_len: u64,
+}
+
+/// Parses the start of the window at the beginning of `data`, returning `None` if `data`
+/// is too short.
+#[cfg(feature = "stream")]
+pub(crate) fn parse_window_header(
+    data: &[u8],
+    target_offset: u64,
+) -> Result<Option<WindowHeader>, Error> {
+    match read_window_header(&mut Cursor::new(data, 0), target_offset) {
+        Err(ref e) if is_truncated(e) => Ok(None),
+        r => r.map(Some),
+    }
+}
+
+fn read_window_header(c: &mut Cursor, target_offset: u64) -> Result<WindowHeader, Error> {
+    let offset = c.pos;
+    let indicator = c.byte()?;
+    if indicator & !(VCD_SOURCE | VCD_TARGET | VCD_ADLER32) != 0 {
+        return Err(Error::InvalidPatch("unknown window indicator"));
+    }
+    let kind = match (indicator & VCD_SOURCE != 0, indicator & VCD_TARGET != 0) {
+        (false, false) => None,
+        (true, false) => Some(SegmentKind::Source),
+        (false, true) => Some(SegmentKind::Target),
+        (true, true) => {
+            return Err(Error::InvalidPatch(
+                "window copies from both source and target",
+            ))
+        }
+    };
+    let segment = match kind {
+        None => None,
+        Some(kind) => {
+            let len = c.varint()?;
+            let position = c.varint()?;
+            let end = position
+                .checked_add(len)
+                .ok_or(Error::InvalidPatch("integer overflow"))?;
+            if kind == SegmentKind::Target && end > target_offset {
+                return Err(Error::InvalidPatch(
+                    "target segment is past the current window",
+                ));
+            }
+            Some(Segment {
+                kind,
+                position,
+                len,
+            })
+        }
+    };
+
+    let enc_len = c.size()?;
+    let len = (c.pos - offset)
+        .checked_add(enc_len)
+        .ok_or(Error::InvalidPatch("integer overflow"))?;
+    let target_len = c.varint()?;
+    Ok(WindowHeader {
+        indicator,
+        segment,
+        len,
+        target_len,
+    })
+}
+
+/// The fields of a window up to its sections.
+pub(crate) struct DeltaHeader {
+    pub(crate) window: WindowHeader,
+    pub(crate) delta_indicator: u8,
+    /// Lengths of the data, instruction and address sections, as stored.
+    pub(crate) sections: [usize; 3],
+    adler32: Option<u32>,
+    /// Number of bytes taken by all of the above.
+    pub(crate) len: usize,
+}
+
+/// Parses the start of the window at the beginning of `data` up to its sections, returning
+/// `None` if `data` is too short.
+pub(crate) fn parse_delta_header(
+    data: &[u8],
+    target_offset: u64,
+) -> Result<Option<DeltaHeader>, Error> {
+    match read_delta_header(&mut Cursor::new(data, 0), target_offset) {
+        Err(ref e) if is_truncated(e) => Ok(None),
+        r => r.map(Some),
+    }
+}
+
+fn read_delta_header(c: &mut Cursor, target_offset: u64) -> Result<DeltaHeader, Error> {
+    let offset = c.pos;
+    let window = read_window_header(c, target_offset)?;
+    let delta_indicator = c.byte()?;
+    if delta_indicator & !(VCD_DATACOMP | VCD_INSTCOMP | VCD_ADDRCOMP) != 0 {
+        return Err(Error::InvalidPatch("unknown delta indicator"));
+    }
+    let data_len = c.size()?;
+    let inst_len = c.size()?;
+    let addr_len = c.size()?;
+    let adler32 = if window.indicator & VCD_ADLER32 != 0 {
+        let b = c.bytes(4)?;
+        Some(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    } else {
+        None
+    };
+    Ok(DeltaHeader {
+        window,
+        delta_indicator,
+        sections: [data_len, inst_len, addr_len],
+        adler32,
+        len: c.pos - offset,
+    })
+}
+
+fn parse_window<'a>(c: &mut Cursor<'a>, target_offset: u64) -> Result<Window<'a>, Error> {
+    let offset = c.pos;
+    let DeltaHeader {
+        window:
+            WindowHeader {
+                segment,
+                len,
+                target_len,
+                ..
+            },
+        delta_indicator,
+        sections: [data_len, inst_len, addr_len],
+        adler32,
+        ..
+    } = read_delta_header(c, target_offset)?;
+    let data = c.bytes(data_len)?;
+    let inst = c.bytes(inst_len)?;
+    let addr = c.bytes(addr_len)?;
+    if c.pos - offset != len {
+        return Err(Error::InvalidPatch("delta encoding length mismatch"));
+    }
+
+    Ok(Window {
+        offset,
+        len,
+        target_offset,
+        target_len,
+        segment,
+        delta_indicator,
+        adler32,
+        data,
+        inst,
+        addr,
+    })
+}
+
+impl<'a> Window<'a> {
+    /// Length of the segment this window copies from, zero if it has none.
+    pub fn segment_len(&self) -> u64 {
+        self.segment.map(|s| s.len).unwrap_or(0)
+    }
+
+    /// Whether any of the sections is compressed by a secondary compressor.
+    pub fn is_compressed(&self) -> bool {
+        self.delta_indicator != 0
+    }
+
+    /// Lengths of the data, instruction and address sections once decompressed.
+    ///
+    /// A compressed section starts with its decompressed length, so this works whatever the
+    /// secondary compressor is.
+    pub fn section_lens(&self) -> Result<[u64; 3], Error> {
+        let len = |section: &[u8], flag: u8| {
+            if self.delta_indicator & flag != 0 {
+                Cursor::new(section, 0).varint()
+            } else {
+                Ok(section.len() as u64)
+            }
+        };
+        Ok([
+            len(self.data, VCD_DATACOMP)?,
+            len(self.inst, VCD_INSTCOMP)?,
+            len(self.addr, VCD_ADDRCOMP)?,
+        ])
+    }
+
+    /// Iterates over the instructions of the window.
+    ///
+    /// Fails if the sections of the window are compressed.
+    pub fn instructions(&self) -> Result<Instructions<'a>, Error> {
+        if self.is_compressed() {
+            return Err(Error::Unsupported("secondary compression"));
+        }
+        Ok(Instructions {
+            data: Cursor::new(self.data, 0),
+            inst: Cursor::new(self.inst, 0),
+            addr: Cursor::new(self.addr, 0),
+            segment_len: self.segment_len(),
+            here: 0,
+            target_len: self.target_len,
+            cache: AddressCache::new(),
+            pending: None,
+            done: false,
+        })
+    }
+}
+
+/// Iterator over the instructions of a [`Window`].
+pub struct Instructions<'a> {
+    data: Cursor<'a>,
+    inst: Cursor<'a>,
+    addr: Cursor<'a>,
+    segment_len: u64,
+    // number of target bytes produced so far
+    here: u64,
+    target_len: u64,
+    cache: AddressCache,
+    // second half of a double instruction code
+    pending: Option<Code>,
+    done: bool,
+}
+
+impl<'a> Iterator for Instructions<'a> {
+    type Item = Result<Instruction<'a>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let r = self.next_inner();
+        match r {
+            Ok(Some(i)) => Some(Ok(i)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+impl<'a> Instructions<'a> {
+    fn next_inner(&mut self) -> Result<Option<Instruction<'a>>, Error> {
+        let code = loop {
+            if let Some(code) = self.pending.take() {
+                break code;
+            }
+            if self.inst.is_empty() {
+                if self.here != self.target_len {
+                    return Err(Error::InvalidPatch("window produces too few bytes"));
+                }
+                if !self.data.is_empty() || !self.addr.is_empty() {
+                    return Err(Error::InvalidPatch("unused data in window"));
+                }
+                return Ok(None);
+            }
+            let (first, second) = default_code(self.inst.byte()?);
+            self.pending = second;
+            if let Some(code) = first {
+                break code;
+            }
+        };
+
+        let len = match code.size {
+            0 => self.inst.varint()?,
+            n => n as u64,
+        };
+        let produced = self
+            .here
+            .checked_add(len)
+            .filter(|&n| n <= self.target_len)
+            .ok_or(Error::InvalidPatch("window produces too many bytes"))?;
+
+        let inst = match code.kind {
+            Kind::Add => Instruction::Add(self.data.bytes(to_usize(len)?)?),
+            Kind::Run => Instruction::Run {
+                byte: self.data.byte()?,
+                len,
+            },
+            Kind::Copy => {
+                let here = self
+                    .segment_len
+                    .checked_add(self.here)
+                    .ok_or(Error::InvalidPatch("integer overflow"))?;
+                let addr = self.cache.decode(&mut self.addr, here, code.mode)?;
+                Instruction::Copy { addr, len }
+            }
+        };
+        self.here = produced;
+        Ok(Some(inst))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    Add,
+    Run,
+    Copy,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Code {
+    kind: Kind,
+    size: u8,
+    mode: u8,
+}
+
+fn code(kind: Kind, size: u8, mode: u8) -> Option<Code> {
+    Some(Code { kind, size, mode })
+}
+
+/// Looks up an instruction code in the default code table of RFC 3284, section 5.6.
+fn default_code(c: u8) -> (Option<Code>, Option<Code>) {
+    match c {
+        0 => (code(Kind::Run, 0, 0), None),
+        1..=18 => (code(Kind::Add, c - 1, 0), None),
+        19..=162 => {
+            let i = c - 19;
+            let size = match i % 16 {
+                0 => 0,
+                s => s + 3,
+            };
+            (code(Kind::Copy, size, i / 16), None)
+        }
+        163..=234 => {
+            let i = c - 163;
+            let r = i % 12;
+            (
+                code(Kind::Add, r / 3 + 1, 0),
+                code(Kind::Copy, r % 3 + 4, i / 12),
+            )
+        }
+        235..=246 => {
+            let i = c - 235;
+            (
+                code(Kind::Add, i % 4 + 1, 0),
+                code(Kind::Copy, 4, 6 + i / 4),
+            )
+        }
+        247..=255 => (code(Kind::Copy, 4, c - 247), code(Kind::Add, 1, 0)),
+    }
+}
+
+/// Number of instructions in instruction code `c` of the default code table, and how many
+/// of them are followed by their size.
+pub(crate) fn code_counts(c: u8) -> (u8, u8) {
+    let (first, second) = default_code(c);
+    let mut counts = (0, 0);
+    for code in first.iter().chain(second.iter()) {
+        counts.0 += 1;
+        counts.1 += (code.size == 0) as u8;
+    }
+    counts
+}
+
+/// The address cache of RFC 3284, section 5.1, with the default sizes.
+pub(crate) struct AddressCache {
+    near: [u64; S_NEAR],
+    next_slot: usize,
+    same: [u64; S_SAME * 256],
+}
+
+impl AddressCache {
+    pub(crate) fn new() -> Self {
+        Self {
+            near: [0; S_NEAR],
+            next_slot: 0,
+            same: [0; S_SAME * 256],
+        }
+    }
+
+    fn update(&mut self, addr: u64) {
+        self.near[self.next_slot] = addr;
+        self.next_slot = (self.next_slot + 1) % S_NEAR;
+        self.same[(addr % (S_SAME as u64 * 256)) as usize] = addr;
+    }
+
+    /// Writes `addr` in the mode taking the fewest bytes to `out`, and returns the mode.
+    pub(crate) fn encode(&mut self, out: &mut Vec<u8>, addr: u64, here: u64) -> u8 {
+        let slot = (addr % (S_SAME as u64 * 256)) as usize;
+        let mode = if self.same[slot] == addr {
+            out.push((slot % 256) as u8);
+            (2 + S_NEAR + slot / 256) as u8
+        } else {
+            let mut best = (0, addr);
+            if here - addr < best.1 {
+                best = (1, here - addr);
+            }
+            for (i, &near) in self.near.iter().enumerate() {
+                if addr >= near && addr - near < best.1 {
+                    best = (2 + i as u8, addr - near);
+                }
+            }
+            write_varint(out, best.1);
+            best.0
+        };
+        self.update(addr);
+        mode
+    }
+
+    fn decode(&mut self, c: &mut Cursor, here: u64, mode: u8) -> Result<u64, Error> {
+        let mode = mode as usize;
+        let addr = match mode {
+            0 => Some(c.varint()?),
+            1 => here.checked_sub(c.varint()?),
+            m if m < 2 + S_NEAR => self.near[m - 2].checked_add(c.varint()?),
+            m if m < 2 + S_NEAR + S_SAME => {
+                Some(self.same[(m - 2 - S_NEAR) * 256 + c.byte()? as usize])
+            }
+            _ => None,
+        };
+        let addr = addr
+            .filter(|&a| a < here)
+            .ok_or(Error::InvalidPatch("copy address out of range"))?;
+        self.update(addr);
+        Ok(addr)
+    }
+}
+
+/// Reads the primitive types of VCDIFF out of a byte slice.
+pub(crate) struct Cursor<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    pub(crate) fn new(buf: &'a [u8], pos: usize) -> Self {
+        Self { buf, pos }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos >= self.buf.len()
+    }
+
+    pub(crate) fn byte(&mut self) -> Result<u8, Error> {
+        let b = *self
+            .buf
+            .get(self.pos)
+            .ok_or(Error::InvalidPatch(TRUNCATED))?;
+        self.pos += 1;
+        Ok(b)
+    }
+
+    pub(crate) fn bytes(&mut self, n: usize) -> Result<&'a [u8], Error> {
+        let end = self
+            .pos
+            .checked_add(n)
+            .filter(|&end| end <= self.buf.len())
+            .ok_or(Error::InvalidPatch(TRUNCATED))?;
+        let b = &self.buf[self.pos..end];
+        self.pos = end;
+        Ok(b)
+    }
+
+    pub(crate) fn varint(&mut self) -> Result<u64, Error> {
+        let mut v = 0u64;
+        loop {
+            let b = self.byte()?;
+            if v >> 57 != 0 {
+                return Err(Error::InvalidPatch("integer overflow"));
+            }
+            v = (v << 7) | (b & 0x7f) as u64;
+            if b & 0x80 == 0 {
+                return Ok(v);
+            }
+        }
+    }
+
+    pub(crate) fn size(&mut self) -> Result<usize, Error> {
+        to_usize(self.varint()?)
+    }
+
+    /// The bytes not read yet.
+    #[cfg(feature = "archive")]
+    pub(crate) fn rest(&self) -> &'a [u8] {
+        self.buf.get(self.pos..).unwrap_or_default()
+    }
+}
+
+/// Number of bytes taken by `v` as a VCDIFF integer.
+pub(crate) fn varint_len(v: u64) -> usize {
+    let bits = 64 - v.leading_zeros() as usize;
+    bits.max(1).div_ceil(7)
+}
+
+/// Appends the file header of a patch with no secondary compressor, code table or
+/// application header to `out`.
+pub(crate) fn write_header(out: &mut Vec<u8>) {
+    out.extend_from_slice(&MAGIC);
+    // header indicator
+    out.push(0);
+}
+
+/// Appends `v` to `out` as a VCDIFF integer: 7 bits per byte, most significant first.
+pub(crate) fn write_varint(out: &mut Vec<u8>, v: u64) {
+    let len = varint_len(v);
+    for i in (0..len).rev() {
+        let b = ((v >> (7 * i)) & 0x7f) as u8;
+        out.push(if i > 0 { b | 0x80 } else { b });
+    }
+}
+
+pub(crate) fn to_usize(v: u64) -> Result<usize, Error> {
+    if v > usize::MAX as u64 {
+        return Err(Error::InvalidPatch("integer overflow"));
+    }
+    Ok(v as usize)
+}
+
+/// Adler-32 checksum, as used by xdelta3 to check the windows.
+pub(crate) fn adler32(data: &[u8]) -> u32 {
+    adler32_update(1, data)
+}
+
+/// Adler-32 of the data `adler` is the checksum of followed by `data`.
+pub(crate) fn adler32_update(adler: u32, data: &[u8]) -> u32 {
+    const BASE: u32 = 65521;
+    // largest n such that the sums can't overflow before being reduced
+    const NMAX: usize = 5552;
+
+    let (mut a, mut b) = (adler & 0xffff, adler >> 16);
+    for chunk in data.chunks(NMAX) {
+        for &x in chunk {
+            a += x as u32;
+            b += a;
+        }
+        a %= BASE;
+        b %= BASE;
+    }
+    (b << 16) | a
+}
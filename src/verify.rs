@@ -0,0 +1,63 @@
+use super::binding;
+use super::error::Error;
+use super::memory::{checksummed, Mode, Sink, SliceStream};
+
+/// Outcome of checking a patch with [`verify`]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Verification {
+    /// Length of the target the patch would produce.
+    pub target_len: u64,
+    /// Number of windows in the patch.
+    pub windows: u64,
+    /// Number of windows carrying an Adler-32 of their target, all of which matched.
+    pub checksummed_windows: u64,
+}
+
+impl Verification {
+    /// Whether every window of the patch carried a checksum.
+    ///
+    /// Without checksums a patch can only be checked for being well-formed and for fitting
+    /// the source; a wrong source of the right size goes unnoticed. The encoders of this
+    /// crate don't write checksums, the `xdelta3` tool does unless told not to.
+    pub fn fully_checksummed(&self) -> bool {
+        self.checksummed_windows == self.windows
+    }
+}
+
+impl Sink for Verification {
+    fn output(&mut self, data: &[u8]) {
+        self.target_len += data.len() as u64;
+    }
+
+    fn window(&mut self, stream: &binding::xd3_stream) {
+        self.windows += 1;
+        self.checksummed_windows += checksummed(stream) as u64;
+    }
+}
+
+/// Checks that `patch` applies to `src` without keeping the target
+///
+/// The patch is decoded as [`decode`](crate::decode) would, but the target is only counted:
+/// memory use stays at one window, however large the target is. xdelta3 checks the Adler-32
+/// of every window that carries one, and a mismatch is reported as an error.
+///
+/// ```
+/// extern crate xdelta3;
+/// use xdelta3::{encode, verify};
+///
+/// fn main() {
+///     let patch = encode(&[1, 2, 3, 4, 5, 6, 7], &[1, 2, 4, 4, 7, 6, 7]).unwrap();
+///     let result = verify(&patch, &[1, 2, 4, 4, 7, 6, 7]).unwrap();
+///     assert_eq!(result.target_len, 7);
+///     assert_eq!(result.windows, 1);
+/// }
+/// ```
+pub fn verify(patch: &[u8], src: &[u8]) -> Result<Verification, Error> {
+    let mut stream = SliceStream::new(src.len())
+        .ok_or_else(|| Error::Xdelta3("failed to set up the decoder".to_owned()))?;
+    let mut verification = Verification::default();
+    stream
+        .run(Mode::Decode, patch, src, &mut verification)
+        .map_err(Error::Xdelta3)?;
+    Ok(verification)
+}
@@ -0,0 +1,342 @@
+//! Runs the xdelta3 state machine on a thread of its own.
+//!
+//! Encoding and decoding are CPU bound and can take seconds, which must not happen on the
+//! thread of an async executor. The worker thread owns the `xd3_stream` and asks the async
+//! side for everything that involves I/O: input, source blocks and writing the output.
+
+use std::sync::{mpsc, Arc};
+use std::thread;
+
+use futures_channel::mpsc as async_mpsc;
+use futures_util::future::poll_fn;
+use log::debug;
+
+use super::alloc::Hooks;
+use super::binding;
+use super::memory::{checksummed, message, rvalue, unknown, Mode};
+use super::stream::Config;
+
+pub(crate) const XD3_DEFAULT_WINSIZE: usize = 1 << 23;
+
+/// Number of events the worker can get ahead of the async side by.
+const EVENT_BACKLOG: usize = 2;
+
+/// Number of source blocks the worker keeps at hand. xdelta3 only holds on to the current
+/// block, and often goes back and forth between a few of them.
+const BLOCK_CACHE: usize = 4;
+
+pub(crate) struct Xd3Stream {
+    inner: binding::xd3_stream,
+    // freed by `xd3_free_stream`, so it has to outlive `inner`
+    hooks: Hooks,
+}
+// The stream only points to memory it owns or that is owned alongside it, and xdelta3
+// keeps no thread-local state, so it can be moved to another thread.
+unsafe impl Send for Xd3Stream {}
+impl Xd3Stream {
+    pub(crate) fn new(config: &Config) -> Self {
+        let inner: binding::xd3_stream = unsafe { std::mem::zeroed() };
+        let hooks = Hooks::new(config.allocator.clone());
+        Self { inner, hooks }
+    }
+}
+impl std::ops::Deref for Xd3Stream {
+    type Target = binding::xd3_stream;
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+impl std::ops::DerefMut for Xd3Stream {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.inner
+    }
+}
+impl Drop for Xd3Stream {
+    fn drop(&mut self) {
+        unsafe {
+            binding::xd3_free_stream(&mut self.inner as *mut _);
+        }
+    }
+}
+
+/// A source block, as the answer to [`Event::GetBlock`].
+pub(crate) struct Block {
+    pub(crate) data: Slice,
+    pub(crate) eof_known: bool,
+    pub(crate) max_blkno: u64,
+    pub(crate) onlastblk: u32,
+}
+
+/// Data lent by the async side, which leaves it alone while the worker holds on to it.
+///
+/// The slice holds on to the owner of the data, so the data outlives the worker's use of it
+/// even if the async side goes away in the meantime.
+pub(crate) struct Slice {
+    ptr: *const u8,
+    len: usize,
+    _owner: Arc<dyn Send + Sync>,
+}
+
+// Only read by the worker, while the async side leaves it alone, and kept alive by `_owner`.
+unsafe impl Send for Slice {}
+
+impl Slice {
+    /// Lends `data`, which `owner` must keep alive and in place until it is dropped.
+    pub(crate) fn new(data: &[u8], owner: Arc<dyn Send + Sync>) -> Self {
+        Self {
+            ptr: data.as_ptr(),
+            len: data.len(),
+            _owner: owner,
+        }
+    }
+
+    /// Lends `data`, which the slice owns.
+    pub(crate) fn from_vec(data: Vec<u8>) -> Self {
+        let owner = Arc::new(data);
+        Self::new(&owner[..], owner.clone())
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+pub(crate) enum Request {
+    /// The next chunk of input, of which the first `len` bytes are valid. An empty chunk
+    /// marks the end of the input.
+    Input(Vec<u8>, usize),
+    /// The next chunk of input, without a copy.
+    Slice(Slice),
+    Block(Block),
+}
+
+impl Request {
+    pub(crate) fn input_len(&self) -> usize {
+        self.input().len()
+    }
+
+    /// The input carried by the request, empty for a block.
+    pub(crate) fn input(&self) -> &[u8] {
+        match self {
+            Request::Input(data, len) => &data[..*len],
+            // kept alive by its owner, which the request holds on to
+            Request::Slice(slice) => unsafe { std::slice::from_raw_parts(slice.ptr, slice.len) },
+            Request::Block(_) => &[],
+        }
+    }
+}
+
+pub(crate) enum Event {
+    /// More input is needed. Carries the previous input buffer, for reuse.
+    Input(Option<Vec<u8>>),
+    Output(Vec<u8>),
+    GetBlock(u64),
+    /// A window is finished, with `avail_in` bytes of the current input left. When
+    /// decoding, `checksummed` tells whether its Adler-32 was checked.
+    WinFinish {
+        avail_in: usize,
+        checksummed: bool,
+    },
+    Done,
+    Error(String),
+}
+
+pub(crate) struct Worker {
+    requests: Option<mpsc::Sender<Request>>,
+    pub(crate) events: async_mpsc::Receiver<Event>,
+}
+
+impl Worker {
+    pub(crate) fn spawn(mode: Mode, config: &Config, blksize: usize, max_winsize: usize) -> Self {
+        let (requests, requests_rx) = mpsc::channel();
+        let (events_tx, events) = async_mpsc::channel(EVENT_BACKLOG);
+        let config = config.clone();
+        // not joined: see `drop`
+        thread::spawn(move || {
+            let mut events = events_tx;
+            let r = run(
+                mode,
+                &config,
+                blksize,
+                max_winsize,
+                &requests_rx,
+                &mut events,
+            );
+            // sent once the stream is freed, so nothing is left behind when the caller sees it
+            match r {
+                Ok(true) => send(&mut events, Event::Done),
+                Ok(false) => false,
+                Err(msg) => send(&mut events, Event::Error(msg)),
+            };
+        });
+        Self {
+            requests: Some(requests),
+            events,
+        }
+    }
+
+    pub(crate) fn send(&self, request: Request) -> Option<()> {
+        self.requests.as_ref()?.send(request).ok()
+    }
+}
+
+impl Drop for Worker {
+    /// Tells the worker to stop, without waiting for it: dropping a future that is still
+    /// running must not block the executor. The worker stops at its next event, and only then
+    /// lets go of the input lent to it.
+    fn drop(&mut self) {
+        // wakes the worker up if it is waiting on us
+        self.requests = None;
+        self.events.close();
+    }
+}
+
+/// Sends an event to the async side, waiting if it is too far behind.
+fn send(events: &mut async_mpsc::Sender<Event>, event: Event) -> bool {
+    futures_executor::block_on(poll_fn(|cx| events.poll_ready(cx))).is_ok()
+        && events.start_send(event).is_ok()
+}
+
+/// Runs the state machine until the end of the input, returning `false` if the async side
+/// went away first.
+fn run(
+    mode: Mode,
+    config: &Config,
+    blksize: usize,
+    max_winsize: usize,
+    requests: &mpsc::Receiver<Request>,
+    events: &mut async_mpsc::Sender<Event>,
+) -> Result<bool, String> {
+    let mut stream = Xd3Stream::new(config);
+    let mut cfg: binding::xd3_config = unsafe { std::mem::zeroed() };
+    cfg.winsize = XD3_DEFAULT_WINSIZE as u32;
+    stream.hooks.install(&mut cfg);
+    let ret = unsafe { binding::xd3_config_stream(&mut stream.inner, &mut cfg) };
+    if ret != 0 {
+        return Err(message(&stream, "xd3_config_stream failed"));
+    }
+
+    // xdelta3 keeps a pointer to the source, so it needs a stable address
+    let mut src: Box<binding::xd3_source> = Box::new(unsafe { std::mem::zeroed() });
+    src.blksize = blksize as u32;
+    src.max_winsize = max_winsize as u64;
+    let ret = unsafe { binding::xd3_set_source(&mut stream.inner, &mut *src) };
+    if ret != 0 {
+        return Err(message(&stream, "xd3_set_source failed"));
+    }
+
+    let mut input: Option<Vec<u8>> = None;
+    // input lent by the async side, held while xdelta3 may point into it
+    let mut _lent: Option<Slice> = None;
+    // the blocks used last, the one `src.curblk` points into at the end
+    let mut blocks: Vec<(u64, Slice)> = Vec::with_capacity(BLOCK_CACHE + 1);
+    let mut eof = false;
+
+    loop {
+        let ret = unsafe {
+            match mode {
+                Mode::Encode => binding::xd3_encode_input(&mut stream.inner),
+                Mode::Decode => binding::xd3_decode_input(&mut stream.inner),
+            }
+        };
+        let ret = rvalue(ret).ok_or_else(|| unknown(&stream, ret))?;
+
+        if !stream.msg.is_null() {
+            debug!("ret={:?}, msg={:?}", ret, unsafe {
+                std::ffi::CStr::from_ptr(stream.msg)
+            },);
+        } else {
+            debug!("ret={:?}", ret,);
+        }
+
+        use binding::xd3_rvalues::*;
+        let event = match ret {
+            XD3_INPUT => {
+                if eof {
+                    return Ok(true);
+                }
+                if !send(events, Event::Input(input.take())) {
+                    return Ok(false);
+                }
+                let (data, ptr, len) = match requests.recv() {
+                    Ok(Request::Input(data, len)) => (Some(data), std::ptr::null(), len),
+                    Ok(Request::Slice(slice)) => {
+                        let (ptr, len) = (slice.ptr, slice.len);
+                        _lent = Some(slice);
+                        (None, ptr, len)
+                    }
+                    _ => return Ok(false),
+                };
+                debug!("read_size={}", len);
+                if len == 0 {
+                    // xd3_set_flags
+                    stream.flags |= binding::xd3_flags::XD3_FLUSH as i32;
+                    eof = true;
+                }
+
+                // xd3_avail_input
+                stream.next_in = data.as_ref().map_or(ptr, |d| d.as_ptr());
+                stream.avail_in = len as u32;
+                input = data;
+                continue;
+            }
+            XD3_OUTPUT => {
+                let out_data = unsafe {
+                    std::slice::from_raw_parts(stream.next_out, stream.avail_out as usize)
+                };
+                let event = Event::Output(out_data.to_vec());
+
+                // xd3_consume_output
+                stream.avail_out = 0;
+                event
+            }
+            XD3_GETSRCBLK => {
+                let blkno = src.getblkno;
+                let block = match blocks.iter().position(|(n, _)| *n == blkno) {
+                    Some(i) => blocks.remove(i).1,
+                    None => {
+                        if !send(events, Event::GetBlock(blkno)) {
+                            return Ok(false);
+                        }
+                        let b = match requests.recv() {
+                            Ok(Request::Block(b)) => b,
+                            _ => return Ok(false),
+                        };
+                        src.eof_known = b.eof_known as i32;
+                        src.max_blkno = b.max_blkno;
+                        src.onlastblk = b.onlastblk;
+                        b.data
+                    }
+                };
+
+                src.curblkno = blkno;
+                src.curblk = block.ptr;
+                src.onblk = block.len as u32;
+                blocks.push((blkno, block));
+                if blocks.len() > BLOCK_CACHE {
+                    blocks.remove(0);
+                }
+                continue;
+            }
+            XD3_WINFINISH => Event::WinFinish {
+                avail_in: stream.avail_in as usize,
+                checksummed: matches!(mode, Mode::Decode) && checksummed(&stream),
+            },
+            XD3_GOTHEADER | XD3_WINSTART => {
+                // do nothing
+                continue;
+            }
+            XD3_TOOFARBACK | XD3_INTERNAL | XD3_INVALID | XD3_INVALID_INPUT | XD3_NOSECOND
+            | XD3_UNIMPLEMENTED => {
+                return Err(message(&stream, "xdelta3 failed"));
+            }
+        };
+        if !send(events, event) {
+            return Ok(false);
+        }
+    }
+}
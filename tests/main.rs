@@ -71,4 +71,1323 @@ mod tests {
         let patch_async = encode2(&input, &source).expect("failed to encode");
         assert_eq!(input, check_decode(&patch_async, &source));
     }
+
+    #[test]
+    fn decode_beyond_estimate() {
+        // a patch made of a single RUN decodes to far more than twice its size
+        let input = vec![7u8; 1 << 20];
+        let patch = encode(&input, &[]).expect("failed to encode");
+        assert!(patch.len() < 1 << 10);
+        assert_eq!(input, check_decode(&patch, &[]));
+    }
+
+    #[test]
+    fn encoder_context() {
+        let dictionary: Vec<u8> = (0..100_000u32).map(|i| (i * 13 % 251) as u8).collect();
+        let mut context = EncoderContext::new(&dictionary).expect("failed to set up");
+        for i in 0..20 {
+            let mut message = dictionary[i * 4000..i * 4000 + 1024].to_vec();
+            message[i] = 0;
+            let patch = context.encode(&message).expect("failed to encode");
+            assert_eq!(message, check_decode(&patch, &dictionary));
+        }
+        let patch = context.encode(&[]).expect("failed to encode");
+        assert!(check_decode(&patch, &dictionary).is_empty());
+    }
+
+    #[test]
+    fn verify_patch() {
+        let src = [1, 2, 4, 4, 7, 6, 7];
+        let patch = encode(&[1, 2, 3, 4, 5, 6, 7], &src).expect("failed to encode");
+        let result = verify(&patch, &src).expect("failed to verify");
+        assert_eq!(result.target_len, 7);
+        assert_eq!(result.windows, 1);
+        assert_eq!(result.checksummed_windows, 0);
+
+        // the patch from `decode`'s example, with an Adler-32 in its window
+        let mut patch = vec![
+            214, 195, 196, 0, 0, 4, 17, 7, 0, 7, 1, 0, 0, 91, 0, 29, 1, 2, 3, 4, 5, 6, 7, 8,
+        ];
+        let result = verify(&patch, &src).expect("failed to verify");
+        assert_eq!(result.target_len, 7);
+        assert!(result.fully_checksummed());
+
+        patch[16] = 0;
+        assert!(verify(&patch, &src).is_err());
+    }
+
+    #[cfg(feature = "stream")]
+    #[test]
+    fn verify_stream() {
+        let src: Vec<u8> = (0..1_000_000u32).map(|i| (i * 7 % 253) as u8).collect();
+        let mut input = src.clone();
+        input[500_000] = 0;
+        let patch = encode2(&input, &src).expect("failed to encode");
+
+        let result =
+            futures::executor::block_on(verify_async(&patch[..], &src[..], &Config::new()))
+                .expect("failed to verify");
+        assert_eq!(result.target_len, input.len() as u64);
+        assert_eq!(
+            result,
+            xdelta3::verify(&patch, &src).expect("failed to verify")
+        );
+
+        let truncated = &patch[..patch.len() / 2];
+        assert!(
+            futures::executor::block_on(verify_async(truncated, &src[..], &Config::new())).is_err()
+        );
+    }
+
+    #[test]
+    fn patch_stats() {
+        let src: Vec<u8> = (0..200_000u32).map(|i| (i * 11 % 241) as u8).collect();
+        let mut input = src[50_000..].to_vec();
+        input.extend_from_slice(&[7; 1000]);
+        input.extend_from_slice(b"something new");
+        let patch = encode(&input, &src).expect("failed to encode");
+
+        let stats =
+            stats::PatchStats::new(&patch, Some(src.len() as u64)).expect("failed to parse");
+        let i = &stats.instructions;
+        assert_eq!(stats.target_len, input.len() as u64);
+        assert_eq!(
+            i.add_bytes + i.source_copy_bytes + i.target_copy_bytes + i.run_bytes,
+            stats.target_len
+        );
+        assert!(i.source_copy_bytes >= 150_000);
+        assert!((150_000..=200_000).contains(&stats.source_used));
+        assert_eq!(stats.unread_windows, 0);
+        assert!(stats.ratio() < 0.1);
+        assert_eq!(stats.data.saved(), 0);
+
+        // a single COPY of 8 bytes from address 2 of a 4 byte source segment: 2 bytes come
+        // from the source, the other 6 from the target it produces
+        let patch = [214, 195, 196, 0, 0, 1, 4, 0, 7, 8, 0, 0, 1, 1, 24, 2];
+        assert_eq!(check_decode(&patch, b"abcd"), b"cdcdcdcd");
+        let stats = stats::PatchStats::new(&patch, Some(4)).expect("failed to parse");
+        assert_eq!(stats.instructions.source_copy_bytes, 2);
+        assert_eq!(stats.instructions.target_copy_bytes, 6);
+        assert_eq!(stats.source_used, 2);
+    }
+
+    #[test]
+    fn target_provenance() {
+        let src: Vec<u8> = (0..100_000u32).map(|i| (i * 17 % 239) as u8).collect();
+        let mut input = src[..40_000].to_vec();
+        input.extend_from_slice(b"a patch changed this");
+        input.extend_from_slice(&src[60_000..]);
+        let patch = encode(&input, &src).expect("failed to encode");
+
+        let regions = provenance::provenance(&patch).expect("failed to parse");
+        assert_eq!(regions.first().map(|r| r.target.start), Some(0));
+        assert_eq!(
+            regions.last().map(|r| r.target.end),
+            Some(input.len() as u64)
+        );
+        for pair in regions.windows(2) {
+            assert_eq!(pair[0].target.end, pair[1].target.start);
+        }
+        for r in &regions {
+            let (start, end) = (r.target.start as usize, r.target.end as usize);
+            match r.origin {
+                provenance::Origin::Source(from) => {
+                    let from = from as usize;
+                    assert_eq!(&input[start..end], &src[from..from + end - start]);
+                }
+                provenance::Origin::Run(byte) => {
+                    assert!(input[start..end].iter().all(|&b| b == byte))
+                }
+                _ => {}
+            }
+        }
+        let new: u64 = regions
+            .iter()
+            .filter(|r| r.is_new())
+            .map(|r| r.target.end - r.target.start)
+            .sum();
+        assert!((20..1000).contains(&new));
+    }
+
+    #[test]
+    fn patch_builder() {
+        let src: Vec<u8> = (0..50_000u32).map(|i| (i * 3 % 251) as u8).collect();
+        let mut builder = builder::PatchBuilder::new();
+        builder
+            .copy_from_source(100, 1000)
+            .add(b"new")
+            .run(0xff, 300)
+            .copy_from_target(1000, 50)
+            .copy_from_source(40_000, 2)
+            .end_window()
+            .add(b"second window")
+            .copy_from_target(1356, 40)
+            .copy_from_source(0, 10_000);
+        let mut expected = src[100..1100].to_vec();
+        expected.extend_from_slice(b"new");
+        expected.extend_from_slice(&[0xff; 300]);
+        expected.extend_from_within(1000..1050);
+        expected.extend_from_slice(&src[40_000..40_002]);
+        expected.extend_from_slice(b"second window");
+        // overlaps what it produces
+        for i in 1356..1396 {
+            expected.push(expected[i]);
+        }
+        expected.extend_from_slice(&src[..10_000]);
+
+        let plain = builder.clone().finish().expect("failed to build");
+        let checked = builder.finish_with_adler32(&src).expect("failed to build");
+        let patch = vcdiff::Patch::parse(&checked).expect("failed to parse");
+        let windows: Vec<_> = patch.windows().collect::<Result<_, _>>().unwrap();
+        assert_eq!(windows.len(), 2);
+        assert_eq!(windows[0].target_len, 1355);
+        assert!(windows.iter().all(|w| w.adler32.is_some()));
+
+        assert_eq!(expected, check_decode(&plain, &src));
+        assert_eq!(expected, check_decode(&checked, &src));
+
+        let mut builder = builder::PatchBuilder::new();
+        builder.add(b"abc").end_window().copy_from_target(0, 3);
+        assert!(builder.finish().is_err());
+    }
+
+    #[cfg(feature = "signing")]
+    #[test]
+    fn signed_patch() {
+        use xdelta3::signing::{decode_verified, sign, verify_signature, SigningKey};
+
+        let key = SigningKey::from_bytes(&[42; 32]);
+        let other = SigningKey::from_bytes(&[43; 32]);
+        let src: Vec<u8> = (0..10_000u32).map(|i| (i * 5 % 247) as u8).collect();
+        let mut target = src.clone();
+        target[5000] = 0;
+        let patch = encode(&target, &src).expect("failed to encode");
+        let signed = sign(&patch, &src, &target, &key);
+
+        assert_eq!(
+            verify_signature(&signed, &key.verifying_key()).expect("bad signature"),
+            &patch[..]
+        );
+        assert!(verify_signature(&signed, &other.verifying_key()).is_err());
+        assert!(verify_signature(&patch, &key.verifying_key()).is_err());
+        let mut tampered = signed.clone();
+        tampered[10] ^= 1;
+        assert!(verify_signature(&tampered, &key.verifying_key()).is_err());
+        assert!(decode_verified(&signed, &target, &key.verifying_key()).is_err());
+
+        let out = decode_verified(&signed, &src, &key.verifying_key()).expect("failed to decode");
+        assert_eq!(out, target);
+    }
+
+    #[test]
+    #[ignore] // needs about 20 GiB of memory
+    fn larger_than_4gib() {
+        // untouched zeroed pages aren't backed by memory until written
+        let mut source = vec![0u8; 5 << 30];
+        let mut input = vec![0u8; (5 << 30) + 3];
+        for (i, b) in b"xdelta3".iter().enumerate() {
+            source[(1 << 32) + i] = *b;
+            input[(1 << 32) + 1 + i] = *b;
+        }
+        input[(5 << 30) + 2] = 1;
+
+        let patch = encode(&input, &source).expect("failed to encode");
+        assert_eq!(input, decode(&patch, &source).expect("failed to decode"));
+    }
+
+    #[cfg(feature = "stream")]
+    struct CountingAlloc {
+        live: std::sync::atomic::AtomicUsize,
+        total: std::sync::atomic::AtomicUsize,
+        fail: bool,
+    }
+
+    #[cfg(feature = "stream")]
+    unsafe impl std::alloc::GlobalAlloc for CountingAlloc {
+        unsafe fn alloc(&self, layout: std::alloc::Layout) -> *mut u8 {
+            use std::sync::atomic::Ordering;
+            if self.fail {
+                return std::ptr::null_mut();
+            }
+            self.live.fetch_add(layout.size(), Ordering::SeqCst);
+            self.total.fetch_add(1, Ordering::SeqCst);
+            std::alloc::System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: std::alloc::Layout) {
+            self.live
+                .fetch_sub(layout.size(), std::sync::atomic::Ordering::SeqCst);
+            std::alloc::System.dealloc(ptr, layout)
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn custom_allocator() {
+        use std::sync::atomic::Ordering;
+        use std::sync::Arc;
+
+        let source = [1, 2, 4, 4, 7, 6, 7];
+        let input = [1, 2, 3, 4, 5, 6, 7];
+        let counter = Arc::new(CountingAlloc {
+            live: Default::default(),
+            total: Default::default(),
+            fail: false,
+        });
+        let config = Config::new().allocator(counter.clone());
+
+        let mut patch = Vec::new();
+        futures::executor::block_on(encode_async_with_config(
+            &input[..],
+            &source[..],
+            &mut patch,
+            &config,
+        ))
+        .expect("failed to encode");
+        assert!(counter.total.load(Ordering::SeqCst) > 0);
+        assert_eq!(counter.live.load(Ordering::SeqCst), 0);
+
+        let mut out = Vec::new();
+        futures::executor::block_on(decode_async_with_config(
+            &patch[..],
+            &source[..],
+            &mut out,
+            &config,
+        ))
+        .expect("failed to decode");
+        assert_eq!(out, input);
+        assert_eq!(counter.live.load(Ordering::SeqCst), 0);
+
+        let failing = Config::new().allocator(Arc::new(CountingAlloc {
+            live: Default::default(),
+            total: Default::default(),
+            fail: true,
+        }));
+        let mut out = Vec::new();
+        let result = futures::executor::block_on(encode_async_with_config(
+            &input[..],
+            &source[..],
+            &mut out,
+            &failing,
+        ));
+        assert!(result.is_err());
+    }
+
+    fn varint(out: &mut Vec<u8>, v: u64) {
+        let mut bytes = vec![(v & 0x7f) as u8];
+        let mut v = v >> 7;
+        while v != 0 {
+            bytes.push((v & 0x7f) as u8 | 0x80);
+            v >>= 7;
+        }
+        out.extend(bytes.iter().rev());
+    }
+
+    /// A window made of a single copy of `len` source bytes starting at `pos`.
+    fn copy_window(pos: u64, len: u64) -> Vec<u8> {
+        let mut body = Vec::new();
+        varint(&mut body, len);
+        body.extend_from_slice(&[0, 0]);
+        let mut inst = vec![19];
+        varint(&mut inst, len);
+        varint(&mut body, inst.len() as u64);
+        body.push(1);
+        body.extend_from_slice(&inst);
+        body.push(0);
+
+        let mut window = vec![1];
+        varint(&mut window, len);
+        varint(&mut window, pos);
+        varint(&mut window, body.len() as u64);
+        window.extend_from_slice(&body);
+        window
+    }
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("xdelta3-rs-{}-{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn in_place_patching() {
+        use std::io::{Seek, SeekFrom, Write};
+
+        let source: Vec<u8> = (0..3000u32).map(|i| (i * 7 / 3) as u8).collect();
+        // rotate the three blocks, so every window overwrites data a later one reads
+        let mut patch = vec![0xd6, 0xc3, 0xc4, 0, 0];
+        patch.extend(copy_window(2000, 1000));
+        patch.extend(copy_window(0, 1000));
+        patch.extend(copy_window(1000, 500));
+        let mut expected = source[2000..].to_vec();
+        expected.extend_from_slice(&source[..1500]);
+        assert_eq!(check_decode(&patch, &source), expected);
+
+        let path = temp_path("in-place");
+        let journal = temp_path("in-place-journal");
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        file.write_all(&source).unwrap();
+
+        xdelta3::inplace::apply_in_place(&mut file, &patch, &journal).expect("failed to patch");
+        assert!(!journal.exists());
+
+        let mut patched = Vec::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut patched).unwrap();
+        assert_eq!(patched, expected);
+
+        // interrupted while writing the second window: what it overwrites and its content
+        // are in the journal, but it isn't committed
+        let mut records = b"XD3INPL1".to_vec();
+        records.extend_from_slice(&(patch.len() as u64).to_be_bytes());
+        records.extend_from_slice(&adler32(&patch).to_be_bytes());
+        records.extend_from_slice(&(source.len() as u64).to_be_bytes());
+        journal_record(&mut records, b'P', 0, Some(&source[..1000]));
+        journal_record(&mut records, b'W', 0, Some(&expected[..1000]));
+        journal_record(&mut records, b'C', 0, None);
+        journal_record(&mut records, b'P', 1000, Some(&source[1000..1500]));
+        journal_record(&mut records, b'W', 1, Some(&expected[1000..2000]));
+        // torn record
+        records.extend_from_slice(b"C\0\0");
+        std::fs::write(&journal, &records).unwrap();
+        let mut interrupted = expected[..1200].to_vec();
+        interrupted.extend_from_slice(&source[1200..]);
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.write_all(&interrupted).unwrap();
+
+        xdelta3::inplace::apply_in_place(&mut file, &patch, &journal).expect("failed to resume");
+        assert!(!journal.exists());
+        let mut patched = Vec::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut patched).unwrap();
+        assert_eq!(patched, expected);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// Appends a record of the journal of `apply_in_place`.
+    fn journal_record(out: &mut Vec<u8>, tag: u8, offset: u64, body: Option<&[u8]>) {
+        let start = out.len();
+        out.push(tag);
+        out.extend_from_slice(&offset.to_be_bytes());
+        if let Some(body) = body {
+            out.extend_from_slice(&(body.len() as u64).to_be_bytes());
+            out.extend_from_slice(body);
+        }
+        let checksum = adler32(&out[start..]);
+        out.extend_from_slice(&checksum.to_be_bytes());
+    }
+
+    fn adler32(data: &[u8]) -> u32 {
+        let (mut a, mut b) = (1u32, 0u32);
+        for &byte in data {
+            a = (a + byte as u32) % 65521;
+            b = (b + a) % 65521;
+        }
+        b << 16 | a
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn checkpoints_and_resume() {
+        use std::io::Cursor;
+
+        let source: Vec<u8> = (0..3000u32).map(|i| (i * 13 / 5) as u8).collect();
+        let mut patch = vec![0xd6, 0xc3, 0xc4, 0, 0];
+        patch.extend(copy_window(1000, 1000));
+        patch.extend(copy_window(0, 1000));
+        patch.extend(copy_window(2000, 1000));
+        let expected = check_decode(&patch, &source);
+
+        let mut checkpoints = Vec::new();
+        let mut out = Vec::new();
+        decode_with_checkpoints(&patch[..], &source[..], &mut out, &Config::new(), |c| {
+            checkpoints.push(c)
+        })
+        .expect("failed to decode");
+        assert_eq!(out, expected);
+        assert_eq!(checkpoints.len(), 3);
+        assert_eq!(checkpoints[2].patch_offset, patch.len() as u64);
+        assert_eq!(checkpoints[2].target_offset, 3000);
+
+        // pretend the decode stopped in the middle of the second window
+        let mut out = Cursor::new(expected[..1500].to_vec());
+        let mut resumed = Vec::new();
+        resume_from(
+            checkpoints[0],
+            Cursor::new(&patch[..]),
+            &source[..],
+            &mut out,
+            &Config::new(),
+            |c| resumed.push(c),
+        )
+        .expect("failed to resume");
+        assert_eq!(out.into_inner(), expected);
+        assert_eq!(resumed, &checkpoints[1..]);
+    }
+
+    #[test]
+    fn random_access() {
+        use xdelta3::index::{decode_range, PatchIndex};
+
+        let source: Vec<u8> = (0..3000u32).map(|i| (i * 11 / 7) as u8).collect();
+        let mut patch = vec![0xd6, 0xc3, 0xc4, 0, 0];
+        patch.extend(copy_window(2000, 1000));
+        patch.extend(copy_window(500, 1000));
+        patch.extend(copy_window(0, 700));
+        let expected = check_decode(&patch, &source);
+
+        let index = PatchIndex::new(&patch).expect("failed to index");
+        assert_eq!(index.header_len(), 5);
+        assert_eq!(index.target_len(), 2700);
+        assert_eq!(index.windows_for(1500..2100), 1..3);
+        assert_eq!(index.windows_for(2700..3000), 0..0);
+
+        for range in &[0..10, 1500..2100, 999..1001, 2600..3000] {
+            let data = decode_range(&patch, &source, range.clone()).expect("failed to decode");
+            let end = (range.end as usize).min(expected.len());
+            assert_eq!(data, &expected[range.start as usize..end]);
+        }
+
+        #[cfg(feature = "stream")]
+        {
+            use futures::io::Cursor;
+            let async_index = futures::executor::block_on(index_async(Cursor::new(&patch)))
+                .expect("failed to index");
+            assert_eq!(async_index, index);
+
+            let mut out = Vec::new();
+            futures::executor::block_on(decode_range_async(
+                &index,
+                Cursor::new(&patch),
+                &source[..],
+                &mut out,
+                1500..2100,
+                &Config::new(),
+            ))
+            .expect("failed to decode");
+            assert_eq!(out, &expected[1500..2100]);
+        }
+    }
+
+    #[test]
+    fn reverse_patch() {
+        let fixure_path = "xdelta3/xdelta3/examples/iOS/xdelta3-ios-test/xdelta3-ios-test/";
+        let v1 = read_file(&format!("{}/{}", fixure_path, "file_v1.bin"));
+        let v2 = read_file(&format!("{}/{}", fixure_path, "file_v2.bin"));
+
+        let (forward, reverse) = encode_pair(&v2, &v1).expect("failed to encode");
+        assert_eq!(check_decode(&forward, &v1), v2);
+        assert_eq!(check_decode(&reverse, &v2), v1);
+    }
+
+    #[test]
+    fn reverse_patch_of_deletions() {
+        let noise = |seed: u32, len: u32| -> Vec<u8> {
+            (seed..seed + len)
+                .map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8)
+                .collect()
+        };
+        let (a, b, deleted) = (
+            noise(0, 1 << 20),
+            noise(1 << 24, 1 << 20),
+            noise(1 << 28, 300_000),
+        );
+        // what only the old version has: data found nowhere else, a repeated pattern and zeros
+        let mut v1 = a.clone();
+        v1.extend_from_slice(&deleted);
+        v1.extend(b"pattern!".iter().cycle().take(100_000));
+        v1.extend_from_slice(&[0; 50_000]);
+        v1.extend_from_slice(&b);
+        let mut v2 = b;
+        v2.extend_from_slice(&a);
+        v2.extend_from_slice(b"new data");
+
+        let (forward, reverse) = encode_pair(&v2, &v1).expect("failed to encode");
+        assert_eq!(check_decode(&forward, &v1), v2);
+        assert_eq!(check_decode(&reverse, &v2), v1);
+        // only the deleted data is stored as it is
+        assert!(reverse.len() < deleted.len() + 10_000, "{}", reverse.len());
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn futures_are_send() {
+        fn assert_send<T: Send>(_: &T) {}
+
+        let (input, src) = (&[0u8][..], &[0u8][..]);
+        let config = Config::new();
+        assert_send(&encode_async(input, src, Vec::new()));
+        assert_send(&decode_async(input, src, Vec::new()));
+        assert_send(&encode_async_with_config(input, src, Vec::new(), &config));
+        assert_send(&decode_async_with_config(input, src, Vec::new(), &config));
+        assert_send(&decode_async_buffered(input, src, Vec::new(), &config));
+        assert_send(&decode_async_with_checkpoints(
+            input,
+            src,
+            Vec::new(),
+            &config,
+            |_| {},
+        ));
+        assert_send(&resume_from_async(
+            Checkpoint::default(),
+            futures::io::Cursor::new(input),
+            src,
+            futures::io::Cursor::new(Vec::new()),
+            &config,
+            |_| {},
+        ));
+        let index = xdelta3::index::PatchIndex::default();
+        assert_send(&index_async(futures::io::Cursor::new(input)));
+        assert_send(&decode_range_async(
+            &index,
+            futures::io::Cursor::new(input),
+            src,
+            Vec::new(),
+            0..1,
+            &config,
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn encoding_does_not_block_the_executor() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        let source: Vec<u8> = (0..1 << 22).map(|i: u32| (i * 7 / 5) as u8).collect();
+        let input: Vec<u8> = (0..1 << 22).map(|i: u32| (i * 7 / 3) as u8).collect();
+        let done = AtomicBool::new(false);
+        let mut ticks = 0;
+
+        let mut patch = Vec::new();
+        let encode = async {
+            let r = encode_async(&input[..], &source[..], &mut patch).await;
+            done.store(true, Ordering::SeqCst);
+            r
+        };
+        let ticker = async {
+            while !done.load(Ordering::SeqCst) {
+                ticks += 1;
+                async_std::task::yield_now().await;
+            }
+        };
+        let (result, ()) = futures::executor::block_on(futures::future::join(encode, ticker));
+        result.expect("failed to encode");
+        assert!(ticks > 1);
+        assert_eq!(input, check_decode(&patch, &source));
+    }
+
+    /// Three windows of 1000 bytes, each made of one instruction.
+    fn runs_patch() -> Vec<u8> {
+        let mut builder = builder::PatchBuilder::new();
+        for byte in 0..3 {
+            builder.run(byte, 1000).end_window();
+        }
+        builder.finish().expect("failed to build")
+    }
+
+    #[test]
+    fn decode_limits() {
+        let patch = runs_patch();
+        let limited = |limits: Limits| match decode_with_limits(&patch, &[], &limits) {
+            Err(Error::LimitExceeded(limit)) => Some(limit),
+            _ => None,
+        };
+        assert_eq!(
+            limited(Limits::new().output_len(2999)),
+            Some("output length")
+        );
+        assert_eq!(
+            limited(Limits::new().window_target_len(999)),
+            Some("window target length")
+        );
+        assert_eq!(limited(Limits::new().window_len(5)), Some("window length"));
+        assert_eq!(
+            limited(Limits::new().instructions(2)),
+            Some("instruction count")
+        );
+
+        let limits = Limits::new()
+            .output_len(3000)
+            .window_target_len(1000)
+            .instructions(3);
+        let out = decode_with_limits(&patch, &[], &limits).expect("failed to decode");
+        assert_eq!(out.len(), 3000);
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn decode_limits_async() {
+        let patch = runs_patch();
+        let decode = |limits: Limits| {
+            let mut out = Vec::new();
+            let input = futures::io::AllowStdIo::new(Trickle {
+                data: &patch,
+                step: 3,
+            });
+            let config = Config::new().limits(limits);
+            futures::executor::block_on(decode_async_with_config(input, &[][..], &mut out, &config))
+                .map(|_| out)
+        };
+        assert!(matches!(
+            decode(Limits::new().output_len(2999)),
+            Err(Error::LimitExceeded(_))
+        ));
+        assert!(matches!(
+            decode(Limits::new().instructions(2)),
+            Err(Error::LimitExceeded(_))
+        ));
+        let out = decode(Limits::new().output_len(3000).instructions(3)).expect("failed to decode");
+        assert_eq!(out.len(), 3000);
+    }
+
+    #[test]
+    fn malformed_patches() {
+        let src: Vec<u8> = (0..40_000u32).map(|i| (i % 251) as u8).collect();
+        let mut target = src.clone();
+        target[1000..1100].copy_from_slice(&[7; 100]);
+        let patch = encode(&target, &src).expect("failed to encode");
+
+        for len in 0..patch.len() {
+            assert!(!matches!(decode(&patch[..len], &src), Some(out) if out == target));
+        }
+        for i in 0..patch.len() {
+            let mut bad = patch.clone();
+            bad[i] ^= 0xff;
+            let _ = decode(&bad, &src);
+            let _ = xdelta3::stats::PatchStats::new(&bad, Some(src.len() as u64));
+        }
+
+        // a source segment at the very end of the 64-bit range
+        let mut far = vec![214, 195, 196, 0, 0, 1];
+        varint(&mut far, 4);
+        varint(&mut far, u64::MAX - 4);
+        far.extend_from_slice(&[7, 4, 0, 0, 1, 1, 20, 0]);
+        assert!(decode(&far, &src).is_none());
+        #[cfg(feature = "stream")]
+        {
+            let mut out = Vec::new();
+            let decoded = futures::executor::block_on(decode_async(&far[..], &src[..], &mut out));
+            assert!(decoded.is_none());
+        }
+    }
+
+    #[test]
+    fn sparse_image() {
+        let src: Vec<u8> = (0..100_000u32).map(|i| (i % 253) as u8).collect();
+        let mut image = vec![0u8; 8 << 20];
+        image[..100_000].copy_from_slice(&src);
+        image[5_000_000..5_000_100].copy_from_slice(&[1; 100]);
+        let patch = sparse::encode_sparse(&image, &src).expect("failed to encode");
+        assert!(patch.len() < 1000);
+        assert_eq!(image, check_decode(&patch, &src));
+
+        let path = temp_path("sparse");
+        let mut out = sparse::SparseFile::new(File::create(&path).unwrap()).unwrap();
+        #[cfg(feature = "stream")]
+        decode_buffered(
+            std::io::Cursor::new(patch.clone()),
+            &src[..],
+            &mut out,
+            &Config::new(),
+        )
+        .expect("failed to decode");
+        #[cfg(not(feature = "stream"))]
+        std::io::Write::write_all(&mut out, &decode(&patch, &src).unwrap()).unwrap();
+        let file = out.into_inner().unwrap();
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::unix::fs::MetadataExt;
+            let metadata = file.metadata().unwrap();
+            assert!(metadata.blocks() * 512 < metadata.len() / 2);
+        }
+        drop(file);
+        assert_eq!(std::fs::read(&path).unwrap(), image);
+
+        // dropping the writer flushes it as well, trailing hole included
+        let mut out = sparse::SparseFile::new(File::create(&path).unwrap()).unwrap();
+        std::io::Write::write_all(&mut out, &image).unwrap();
+        drop(out);
+        assert_eq!(std::fs::read(&path).unwrap(), image);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn file_to_file() {
+        use xdelta3::file::{apply_file, create_patch_file, FileOptions};
+
+        let dir = temp_path("files");
+        std::fs::create_dir_all(&dir).unwrap();
+        let old: Vec<u8> = (0..300_000u32).map(|i| (i * 3 % 251) as u8).collect();
+        let mut new = old.clone();
+        new[123_456..123_466].copy_from_slice(b"0123456789");
+        let (source, target, patch) = (dir.join("app"), dir.join("app.new"), dir.join("patch"));
+        std::fs::write(&source, &old).unwrap();
+        std::fs::write(&target, &new).unwrap();
+
+        create_patch_file(&source, &target, &patch, &FileOptions::new()).expect("failed to encode");
+        assert!(std::fs::metadata(&patch).unwrap().len() < 1000);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&source, std::fs::Permissions::from_mode(0o640)).unwrap();
+        }
+        let backup = dir.join("app.orig");
+        let options = FileOptions::new()
+            .preserve_permissions(true)
+            .preserve_mtime(true)
+            .backup(&backup);
+        apply_file(&source, &patch, &source, &options).expect("failed to decode");
+
+        assert_eq!(std::fs::read(&source).unwrap(), new);
+        assert_eq!(std::fs::read(&backup).unwrap(), old);
+        let (patched, original) = (
+            std::fs::metadata(&source).unwrap(),
+            std::fs::metadata(&backup).unwrap(),
+        );
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(patched.permissions().mode() & 0o777, 0o640);
+        }
+        assert_eq!(patched.modified().unwrap(), original.modified().unwrap());
+
+        // a failed run leaves the target alone, and no temporary file behind
+        std::fs::write(&patch, b"not a patch").unwrap();
+        assert!(apply_file(&backup, &patch, &source, &FileOptions::new()).is_err());
+        assert_eq!(std::fs::read(&source).unwrap(), new);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 4);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// A ZIP archive of deflated entries, without CRCs or dates.
+    #[cfg(feature = "archive")]
+    fn zip(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let entries: Vec<_> = entries
+            .iter()
+            .map(|&(name, data)| (name, data, miniz_oxide::deflate::compress_to_vec(data, 6)))
+            .collect();
+        zip_deflated(&entries)
+    }
+
+    /// A ZIP archive of entries already deflated.
+    #[cfg(feature = "archive")]
+    fn zip_deflated(entries: &[(&str, &[u8], Vec<u8>)]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut central = Vec::new();
+        for (name, data, stored) in entries {
+            let mut fields = vec![20, 0, 0, 0, 8, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+            fields.extend_from_slice(&(stored.len() as u32).to_le_bytes());
+            fields.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            fields.extend_from_slice(&(name.len() as u16).to_le_bytes());
+
+            central.extend_from_slice(&[0x50, 0x4b, 0x01, 0x02, 20, 0]);
+            central.extend_from_slice(&fields);
+            central.extend_from_slice(&[0; 12]);
+            central.extend_from_slice(&(out.len() as u32).to_le_bytes());
+            central.extend_from_slice(name.as_bytes());
+
+            out.extend_from_slice(&[0x50, 0x4b, 0x03, 0x04]);
+            out.extend_from_slice(&fields);
+            out.extend_from_slice(&[0, 0]);
+            out.extend_from_slice(name.as_bytes());
+            out.extend_from_slice(stored);
+        }
+        let offset = out.len() as u32;
+        out.extend_from_slice(&central);
+        out.extend_from_slice(&[0x50, 0x4b, 0x05, 0x06, 0, 0, 0, 0]);
+        out.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+        out.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+        out.extend_from_slice(&(central.len() as u32).to_le_bytes());
+        out.extend_from_slice(&offset.to_le_bytes());
+        out.extend_from_slice(&[0, 0]);
+        out
+    }
+
+    #[test]
+    #[cfg(feature = "archive")]
+    fn archive_patch() {
+        use xdelta3::archive::{decode_archive, encode_archive, expanded_entries};
+
+        let classes: Vec<u8> = (0..200_000u32)
+            .flat_map(|i| format!("class{} ", i * 7 % 1009).into_bytes())
+            .collect();
+        let mut changed = classes.clone();
+        changed[1000..1010].copy_from_slice(b"0123456789");
+        let old = zip(&[("classes.dex", &classes), ("README", b"version 1")]);
+        let new = zip(&[("classes.dex", &changed), ("README", b"version 2")]);
+
+        let patch = encode_archive(&new, &old).expect("failed to encode");
+        let plain = encode(&new, &old).expect("failed to encode");
+        assert!(patch.len() * 10 < plain.len());
+        assert_eq!(expanded_entries(&patch).unwrap(), 2);
+        assert_eq!(decode_archive(&patch, &old).expect("failed to decode"), new);
+        // plain patches go through as they are
+        assert_eq!(decode_archive(&plain, &old).expect("failed to decode"), new);
+
+        // an entry deflated by something else than miniz_oxide is diffed as it is stored
+        let notes = |version: u32| -> Vec<u8> {
+            (0..100u32)
+                .flat_map(|i| format!("note {} of version {}\n", i, version).into_bytes())
+                .collect()
+        };
+        let (notes1, notes2) = (notes(1), notes(2));
+        let old = zip_deflated(&[
+            (
+                "classes.dex",
+                &classes,
+                miniz_oxide::deflate::compress_to_vec(&classes, 6),
+            ),
+            ("NOTES", &notes1, stored_blocks(&notes1)),
+        ]);
+        let new = zip_deflated(&[
+            (
+                "classes.dex",
+                &changed,
+                miniz_oxide::deflate::compress_to_vec(&changed, 6),
+            ),
+            ("NOTES", &notes2, stored_blocks(&notes2)),
+        ]);
+        let patch = encode_archive(&new, &old).expect("failed to encode");
+        assert_eq!(
+            expanded_entries(&patch).unwrap(),
+            1,
+            "only classes.dex is expanded"
+        );
+        assert_eq!(decode_archive(&patch, &old).expect("failed to decode"), new);
+    }
+
+    #[test]
+    #[cfg(feature = "archive")]
+    fn archive_patch_zlib() {
+        use flate2::{write::DeflateEncoder, Compression};
+        use std::io::Write;
+        use xdelta3::archive::{decode_archive, encode_archive, expanded_entries};
+
+        // archives written with zlib, as most JAR and APK files are
+        let deflate = |data: &[u8]| {
+            let mut e = DeflateEncoder::new(Vec::new(), Compression::default());
+            e.write_all(data).unwrap();
+            e.finish().unwrap()
+        };
+        let classes: Vec<u8> = (0..200_000u32)
+            .flat_map(|i| format!("class{} ", i * 7 % 1009).into_bytes())
+            .collect();
+        let mut changed = classes.clone();
+        changed[1000..1010].copy_from_slice(b"0123456789");
+        assert_ne!(
+            deflate(&classes),
+            miniz_oxide::deflate::compress_to_vec(&classes, 6)
+        );
+        let old = zip_deflated(&[("classes.dex", &classes, deflate(&classes))]);
+        let new = zip_deflated(&[("classes.dex", &changed, deflate(&changed))]);
+
+        let patch = encode_archive(&new, &old).expect("failed to encode");
+        assert_eq!(expanded_entries(&patch).unwrap(), 0);
+        assert_eq!(patch, encode(&new, &old).expect("failed to encode"));
+        assert_eq!(decode_archive(&patch, &old).expect("failed to decode"), new);
+    }
+
+    /// Deflates `data` as two stored blocks, which no level of miniz_oxide gives back.
+    #[cfg(feature = "archive")]
+    fn stored_blocks(data: &[u8]) -> Vec<u8> {
+        let (first, last) = data.split_at(data.len() / 2);
+        let mut out = Vec::new();
+        for &(bfinal, block) in &[(0u8, first), (1, last)] {
+            out.push(bfinal);
+            out.extend_from_slice(&(block.len() as u16).to_le_bytes());
+            out.extend_from_slice(&(!(block.len() as u16)).to_le_bytes());
+            out.extend_from_slice(block);
+        }
+        out
+    }
+
+    /// Hands out at most `step` bytes per read, like a pipe or a socket.
+    #[cfg(feature = "stream")]
+    struct Trickle<'a> {
+        data: &'a [u8],
+        step: usize,
+    }
+
+    #[cfg(feature = "stream")]
+    impl std::io::Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let n = self.step.min(buf.len()).min(self.data.len());
+            buf[..n].copy_from_slice(&self.data[..n]);
+            self.data = &self.data[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn partial_reads() {
+        let source: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        let mut input = source.clone();
+        input[50_000..50_100].copy_from_slice(&[0u8; 100]);
+
+        let mut patch = Vec::new();
+        futures::executor::block_on(encode_async(
+            futures::io::AllowStdIo::new(Trickle {
+                data: &input,
+                step: 7,
+            }),
+            futures::io::AllowStdIo::new(Trickle {
+                data: &source,
+                step: 1000,
+            }),
+            &mut patch,
+        ))
+        .expect("failed to encode");
+        assert_eq!(input, check_decode(&patch, &source));
+
+        let mut out = Vec::new();
+        decode_with_checkpoints(
+            Trickle {
+                data: &patch,
+                step: 1,
+            },
+            Trickle {
+                data: &source,
+                step: 3,
+            },
+            &mut out,
+            &Config::new(),
+            |_| {},
+        )
+        .expect("failed to decode");
+        assert_eq!(input, out);
+    }
+
+    /// Source kept as blocks in a map, the way an object store would.
+    #[cfg(feature = "stream")]
+    struct BlockStore {
+        blocks: std::collections::HashMap<u64, Vec<u8>>,
+        blksize: usize,
+    }
+
+    #[cfg(feature = "stream")]
+    impl xdelta3::source::Source for BlockStore {
+        fn get_block(&mut self, blkno: u64, blksize: usize) -> std::io::Result<&[u8]> {
+            assert_eq!(blksize, self.blksize);
+            Ok(self.blocks.get(&blkno).map(|b| &b[..]).unwrap_or(&[]))
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn custom_source() {
+        let source: Vec<u8> = (0..3_000_000u32).map(|i| (i / 3 % 253) as u8).collect();
+        let mut input = source[1000..].to_vec();
+        input[2_000_000..2_000_100].copy_from_slice(&[1u8; 100]);
+
+        let mut patch = Vec::new();
+        encode_with_source(&input[..], &source[..], &mut patch, &Config::new())
+            .expect("failed to encode");
+        assert_eq!(input, check_decode(&patch, &source));
+
+        let blksize = 1 << 20;
+        let store = BlockStore {
+            blocks: source
+                .chunks(blksize)
+                .enumerate()
+                .map(|(i, b)| (i as u64, b.to_vec()))
+                .collect(),
+            blksize,
+        };
+        let mut out = Vec::new();
+        decode_with_source(&patch[..], store, &mut out, &Config::new()).expect("failed to decode");
+        assert_eq!(input, out);
+    }
+
+    /// Serves `data` over HTTP, answering range requests only, and counts the requests.
+    #[cfg(feature = "http")]
+    fn serve(data: Vec<u8>) -> (String, std::sync::Arc<std::sync::atomic::AtomicUsize>) {
+        serve_on(std::net::TcpListener::bind("127.0.0.1:0").unwrap(), data)
+    }
+
+    #[cfg(feature = "http")]
+    fn serve_on(
+        listener: std::net::TcpListener,
+        data: Vec<u8>,
+    ) -> (String, std::sync::Arc<std::sync::atomic::AtomicUsize>) {
+        use std::io::{BufRead, BufReader, Write};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let url = format!("http://{}/old.bin", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        let data = Arc::new(data);
+        std::thread::spawn(move || {
+            for conn in listener.incoming() {
+                let mut conn = conn.unwrap();
+                let (data, counter) = (data.clone(), counter.clone());
+                std::thread::spawn(move || {
+                    let mut reader = BufReader::new(conn.try_clone().unwrap());
+                    let mut line = String::new();
+                    while reader.read_line(&mut line).unwrap_or(0) > 0 {
+                        let mut range = (0, 0);
+                        loop {
+                            line.clear();
+                            reader.read_line(&mut line).unwrap();
+                            if line.trim_end().is_empty() {
+                                break;
+                            }
+                            if let Some(r) = line.trim_end().strip_prefix("Range: bytes=") {
+                                let (a, b) = r.split_once('-').unwrap();
+                                range = (a.parse::<usize>().unwrap(), b.parse::<usize>().unwrap());
+                            }
+                        }
+                        line.clear();
+                        counter.fetch_add(1, Ordering::SeqCst);
+
+                        let (a, b) = range;
+                        if a >= data.len() {
+                            write!(
+                                conn,
+                                "HTTP/1.1 416 Range Not Satisfiable\r\nContent-Range: bytes */{}\r\nContent-Length: 0\r\n\r\n",
+                                data.len()
+                            )
+                            .unwrap();
+                            continue;
+                        }
+                        let b = b.min(data.len() - 1);
+                        write!(
+                            conn,
+                            "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {}-{}/{}\r\nContent-Length: {}\r\n\r\n",
+                            a,
+                            b,
+                            data.len(),
+                            b - a + 1
+                        )
+                        .unwrap();
+                        conn.write_all(&data[a..=b]).unwrap();
+                    }
+                });
+            }
+        });
+        (url, requests)
+    }
+
+    #[test]
+    #[cfg(feature = "http")]
+    fn http_source() {
+        use std::sync::atomic::Ordering;
+        use xdelta3::http::HttpSource;
+        use xdelta3::source::AsyncSource;
+
+        let source: Vec<u8> = (0..2_500_000u32).map(|i| (i / 7 % 241) as u8).collect();
+        let (url, requests) = serve(source.clone());
+
+        let mut http = HttpSource::new(&url).unwrap().cache_blocks(2);
+        let mut block = |blkno| {
+            futures::executor::block_on(futures::future::poll_fn(|cx| {
+                std::pin::Pin::new(&mut http)
+                    .poll_block(cx, blkno, 1 << 20)
+                    .map_ok(<[u8]>::to_vec)
+            }))
+            .unwrap()
+        };
+        assert_eq!(block(1), &source[1 << 20..2 << 20]);
+        assert_eq!(block(2), &source[2 << 20..]);
+        assert_eq!(block(1), &source[1 << 20..2 << 20]);
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+        assert!(block(3).is_empty());
+        assert_eq!(http.size(), Some(source.len() as u64));
+
+        let mut input = source.clone();
+        input[1_500_000..1_500_010].copy_from_slice(b"0123456789");
+        let patch = encode(&input, &source).expect("failed to encode");
+        let mut out = Vec::new();
+        futures::executor::block_on(decode_async_with_source(
+            &patch[..],
+            HttpSource::new(&url).unwrap(),
+            &mut out,
+            &Config::new(),
+        ))
+        .expect("failed to decode");
+        assert_eq!(input, out);
+
+        // IPv6 addresses are written in brackets
+        if let Ok(listener) = std::net::TcpListener::bind("[::1]:0") {
+            let (url, _) = serve_on(listener, source.clone());
+            assert!(url.starts_with("http://[::1]:"));
+            let mut http = HttpSource::new(&url).unwrap();
+            let block = futures::executor::block_on(futures::future::poll_fn(|cx| {
+                std::pin::Pin::new(&mut http)
+                    .poll_block(cx, 0, 1 << 20)
+                    .map_ok(<[u8]>::to_vec)
+            }))
+            .expect("failed to fetch");
+            assert_eq!(block, &source[..1 << 20]);
+        }
+        assert!(HttpSource::new("http://[::1/old.bin").is_err());
+        assert!(HttpSource::new("http://[::1]x/old.bin").is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "http")]
+    fn http_bad_responses() {
+        use std::io::{BufRead, BufReader, Write};
+        use xdelta3::http::HttpSource;
+        use xdelta3::source::AsyncSource;
+
+        for response in [
+            // the range is ignored, and the whole file would follow
+            "HTTP/1.1 200 OK\r\nContent-Length: 1000000000000\r\n\r\n".to_owned(),
+            "HTTP/1.1 206 Partial Content\r\nContent-Length: 1000000000000\r\n\r\n".to_owned(),
+            // another range than the one asked for
+            "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes 5-9/100\r\nContent-Length: 5\r\n\r\n"
+                .to_owned(),
+            // a header line that doesn't end
+            format!("HTTP/1.1 206 Partial Content\r\nX-Padding: {}", "a".repeat(1 << 20)),
+        ] {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let url = format!("http://{}/old.bin", listener.local_addr().unwrap());
+            std::thread::spawn(move || {
+                let (mut conn, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(conn.try_clone().unwrap());
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 2 {
+                    line.clear();
+                }
+                // the client may hang up before it has read it all
+                let _ = conn.write_all(response.as_bytes());
+                // no body: the connection stays open until the client gives up
+                let _ = reader.read_line(&mut line);
+            });
+
+            let mut http = HttpSource::new(&url).unwrap();
+            let result = futures::executor::block_on(futures::future::poll_fn(|cx| {
+                std::pin::Pin::new(&mut http)
+                    .poll_block(cx, 0, 1 << 20)
+                    .map_ok(<[u8]>::to_vec)
+            }));
+            assert!(result.is_err());
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn buffered_input() {
+        let source: Vec<u8> = (0..1_000_000u32).map(|i| (i % 239) as u8).collect();
+        let mut input = source.clone();
+        input.splice(300_000..300_000, b"inserted".iter().cloned());
+
+        let mut patch = Vec::new();
+        let reader = std::io::BufReader::with_capacity(4096, std::io::Cursor::new(input.clone()));
+        encode_buffered(reader, &source[..], &mut patch, &Config::new()).expect("failed to encode");
+        assert_eq!(input, check_decode(&patch, &source));
+
+        let mut out = Vec::new();
+        futures::executor::block_on(decode_async_buffered(
+            futures::io::Cursor::new(patch),
+            &source[..],
+            &mut out,
+            &Config::new(),
+        ))
+        .expect("failed to decode");
+        assert_eq!(input, out);
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn read_ahead() {
+        let source: Vec<u8> = (0..5_000_000u32).map(|i| (i / 11 % 233) as u8).collect();
+        let mut input = source.clone();
+        input[4_500_000..4_500_004].copy_from_slice(b"tail");
+        let patch = encode(&input, &source).expect("failed to encode");
+
+        for &blocks in &[0, 1, 4, 100] {
+            let config = Config::new().read_ahead(blocks);
+            let mut out = Vec::new();
+            futures::executor::block_on(decode_async_with_config(
+                &patch[..],
+                futures::io::AllowStdIo::new(Trickle {
+                    data: &source,
+                    step: 100_000,
+                }),
+                &mut out,
+                &config,
+            ))
+            .expect("failed to decode");
+            assert_eq!(input, out);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn read_ahead_keeps_window() {
+        const MIB: u64 = 1 << 20;
+        let source: Vec<u8> = (0..90 * MIB as u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8)
+            .collect();
+        // each copy going back to the oldest block of the 64 MiB source window
+        let mut builder = builder::PatchBuilder::new();
+        let mut expected = Vec::new();
+        for &(last, first) in &[(79 * MIB, 16 * MIB + 5), (89 * MIB, 26 * MIB + 5)] {
+            for &offset in &[last, first] {
+                builder.copy_from_source(offset, 4096).end_window();
+                expected.extend_from_slice(&source[offset as usize..][..4096]);
+            }
+        }
+        let patch = builder.finish().expect("failed to build");
+
+        for &blocks in &[0, 4, 32] {
+            let config = Config::new().read_ahead(blocks);
+            let mut out = Vec::new();
+            futures::executor::block_on(decode_async_with_config(
+                &patch[..],
+                &source[..],
+                &mut out,
+                &config,
+            ))
+            .expect("failed to decode");
+            assert_eq!(expected, out);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn byte_streams() {
+        use futures::stream::{self, TryStreamExt};
+
+        let source: Vec<u8> = (0..300_000u32).map(|i| (i % 199) as u8).collect();
+        let mut input = source.clone();
+        input.truncate(250_000);
+        input.extend_from_slice(b"appended");
+        let chunks = |data: &[u8]| {
+            let chunks: Vec<_> = data
+                .chunks(4000)
+                .map(|c| Ok::<_, std::io::Error>(c.to_vec()))
+                .collect();
+            stream::iter(chunks)
+        };
+
+        let patch: Vec<_> = futures::executor::block_on(
+            encode_stream(chunks(&input), &source[..], &Config::new()).try_collect(),
+        )
+        .expect("failed to encode");
+        let patch = patch.concat();
+        assert_eq!(input, check_decode(&patch, &source));
+
+        let out: Vec<_> = futures::executor::block_on(
+            decode_stream(chunks(&patch), &source[..], &Config::new()).try_collect(),
+        )
+        .expect("failed to decode");
+        assert_eq!(input, out.concat());
+
+        let garbage = futures::executor::block_on(
+            decode_stream(chunks(b"not a patch"), &source[..], &Config::new())
+                .try_collect::<Vec<_>>(),
+        );
+        assert!(garbage.is_err());
+    }
 }